@@ -2,15 +2,45 @@ use crate::compiler::Token;
 
 macro_rules! build_tokens {
     ($arr_var:ident, $($word:literal),+) => {
-        const $arr_var : [&str; $({$word; 1}+)+0] = [
+        const $arr_var : [&str; $({let _ = $word; 1}+)+0] = [
             $($word),+
         ];
     };
 }
 
-build_tokens!(OPERATIONS, "create", "add_row", "headers", "apply", "view");
+build_tokens!(
+    OPERATIONS,
+    "create",
+    "add_row",
+    "headers",
+    "apply",
+    "view",
+    "define",
+    "prompt",
+    "load",
+    "plot",
+    "prompt_into",
+    "fill_column",
+    "set",
+    "delete_row",
+    "insert_row",
+    "update_row",
+    "add_column",
+    "drop_column",
+    "rename_column",
+    "move_column",
+    "reorder_columns",
+    "sort",
+    "filter",
+    "group_by",
+    "pivot",
+    "join",
+    "left_join",
+    "outer_join",
+    "append"
+);
 build_tokens!(MODIFIERS, "with", "and");
-build_tokens!(DMODIFIERS, "title");
+build_tokens!(DMODIFIERS, "title", "headers", "delimiter");
 
 // Note: Current implementation assumes the keyboard layout uses keys such that they have a
 // contiguous range of UTF encoding, which is true for english language.
@@ -31,7 +61,7 @@ impl<const N: usize, const P: usize> KeyBoard<N, P> {
     }
     pub const fn new(layout: &[[char; P]; N]) -> Self {
         let mut smallest = 'z' as usize;
-        let mut largest = 0 as usize;
+        let mut largest = 0;
         let mut i = 0;
         while i < N {
             let mut j = 0;
@@ -46,8 +76,8 @@ impl<const N: usize, const P: usize> KeyBoard<N, P> {
             }
             i += 1;
         }
-        let smallest_char = smallest as usize;
-        let largest_char = largest as usize;
+        let smallest_char = smallest;
+        let largest_char = largest;
         assert!(largest_char + 1 == N + smallest_char);
         let mut new_layout = [[0; P]; N];
         let mut i = 0;
@@ -144,28 +174,32 @@ pub(crate) fn degree_of_closeness(key: &str, matcher: &str) -> u32 {
      * this returns a fictional cost of changing key to matcher
      * the smaller the better
      *
-     * one can use dynamic programming to make this faster
-     * but we are actually working an really small strings,
-     * so it is not worth it
+     * cost[i][j] is the cost of changing key[i..] to matcher[j..], filled from the ends
+     * of both words so every suffix pair is only computed once
      */
-    if key.is_empty() || matcher.is_empty() {
-        return ((key.len() + matcher.len()) << 3) as u32;
-    };
-    let (key_c, match_c) = (key.chars().next().unwrap(), matcher.chars().next().unwrap());
-    if key_c == match_c {
-        return degree_of_closeness(&key[1..], &matcher[1..]);
-    }
-    if !key_c.is_ascii_alphabetic() {
-        return degree_of_closeness(&key[1..], matcher);
-    }
-    if !match_c.is_ascii_alphabetic() {
-        return degree_of_closeness(key, &matcher[1..]);
+    let (key, matcher): (Vec<char>, Vec<char>) = (key.chars().collect(), matcher.chars().collect());
+    let (n, m) = (key.len(), matcher.len());
+    let mut cost = vec![vec![0_u32; m + 1]; n + 1];
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            cost[i][j] = if i == n || j == m {
+                (((n - i) + (m - j)) << 3) as u32
+            } else if key[i] == matcher[j] {
+                cost[i + 1][j + 1]
+            } else if !key[i].is_ascii_alphabetic() {
+                cost[i + 1][j]
+            } else if !matcher[j].is_ascii_alphabetic() {
+                cost[i][j + 1]
+            } else {
+                let delete_cost = cost[i + 1][j] + (1 << 3);
+                let insert_cost = cost[i][j + 1] + (1 << 3);
+                let replace_cost =
+                    cost[i + 1][j + 1] + KEYBOARD.lookup(key[i], matcher[j]).unwrap_or(0);
+                delete_cost.min(insert_cost).min(replace_cost)
+            };
+        }
     }
-    let delete_cost = degree_of_closeness(&key[1..], matcher) + (1 << 3);
-    let insert_cost = degree_of_closeness(key, &matcher[1..]) + (1 << 3);
-    let replace_cost = degree_of_closeness(&key[1..], &matcher[1..])
-        + KEYBOARD.lookup(key_c, match_c).unwrap_or(0);
-    delete_cost.min(insert_cost).min(replace_cost)
+    cost[0][0]
 }
 
 pub(crate) fn keyboard_distance_matcher<'a>(token: &'a str, token_type: Token<'a>) -> &'a str {
@@ -1,10 +1,12 @@
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr};
 
 use crate::{
-    compiler::{Cell, Table, VM},
-    suggestion,
+    compiler::{Cell, Table},
+    formula::{CellRef, Function},
+    suggestion, warn,
 };
-use eyre::{bail, Result};
+use eyre::{bail, eyre, Result};
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Operation {
     Add,
     Subtract,
@@ -14,11 +16,12 @@ pub(crate) enum Operation {
     And,
     Or,
     Xor,
-}
-
-pub(crate) enum StorageType {
-    Accumulator(usize, usize),
-    Register(usize),
+    Less,
+    Greater,
+    Equal,
+    NotEqual,
+    LessEqual,
+    GreaterEqual,
 }
 
 impl Operation {
@@ -29,10 +32,15 @@ impl Operation {
             Self::Multiply => a * b,
             Self::Divide => a / b,
             Self::Mod => a % b,
-            _ => todo!(),
-            /* Self::And => (a as u64 & b as u64) as f64,
-            Self::Or => (a as u64 | b as u64) as f64,
-            Self::Xor => (a as u64 ^ b as u64) as f64, */
+            Self::And => a & b,
+            Self::Or => a | b,
+            Self::Xor => a ^ b,
+            Self::Equal => Ok(Cell::Boolean(a.compare(&b).is_ok_and(|o| o.is_eq()))),
+            Self::NotEqual => Ok(Cell::Boolean(!a.compare(&b).is_ok_and(|o| o.is_eq()))),
+            Self::Less => Ok(Cell::Boolean(a.compare(&b)?.is_lt())),
+            Self::Greater => Ok(Cell::Boolean(a.compare(&b)?.is_gt())),
+            Self::LessEqual => Ok(Cell::Boolean(a.compare(&b)?.is_le())),
+            Self::GreaterEqual => Ok(Cell::Boolean(a.compare(&b)?.is_ge())),
         }
     }
 }
@@ -49,297 +57,764 @@ impl FromStr for Operation {
             "&" => Ok(Self::And),
             "|" => Ok(Self::Or),
             "^" => Ok(Self::Xor),
+            "<" => Ok(Self::Less),
+            ">" => Ok(Self::Greater),
+            "==" => Ok(Self::Equal),
+            "!=" | "<>" => Ok(Self::NotEqual),
+            "<=" => Ok(Self::LessEqual),
+            ">=" => Ok(Self::GreaterEqual),
             _ => bail!("Invalid operation."),
         }
     }
 }
 
+/// Splits a script on `!`, except where it is part of the `!=` operator.
+fn split_script(script: &str) -> impl Iterator<Item = &str> {
+    let mut rest = Some(script);
+    std::iter::from_fn(move || {
+        let text = rest?;
+        let mut search = 0;
+        while let Some(idx) = text[search..].find('!').map(|idx| idx + search) {
+            if text[idx + 1..].starts_with('=') {
+                search = idx + 1;
+                continue;
+            }
+            rest = Some(&text[idx + 1..]);
+            return Some(&text[..idx]);
+        }
+        rest = None;
+        Some(text)
+    })
+}
+
+/// A single step of a compiled postfix expression.
+#[derive(Debug)]
+pub(crate) enum Instruction {
+    Push(Cell),
+    /// `~op`, value of the storage cell
+    Storage,
+    /// `~cell`
+    CurrentCell,
+    /// `~cell(i,j)` or `~cell(B2)`
+    CellAt(usize, usize),
+    /// `~cell(i,"header")`, the column is looked up when the closure runs
+    CellAtHeader(usize, String),
+    /// `~cell.x`
+    CurrentRow,
+    /// `~cell.y`
+    CurrentColumn,
+    /// `~fn name`
+    Call(String),
+    /// `?`, keeps the then or else value depending on the condition
+    Select,
+    Apply(Operation),
+}
+
+impl Instruction {
+    /// Number of values popped from and pushed to the stack.
+    fn arity(&self) -> (usize, usize) {
+        match self {
+            Instruction::Select => (3, 1),
+            Instruction::Apply(_) => (2, 1),
+            _ => (0, 1),
+        }
+    }
+}
+
+/// Splits on whitespace, except inside double quotes.
+fn postfix_tokens(input: &str) -> impl Iterator<Item = &str> {
+    let mut rest = input.trim_start();
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c.is_whitespace() && !in_quotes
+            })
+            .map_or(rest.len(), |(idx, _)| idx);
+        let token = &rest[..end];
+        rest = rest[end..].trim_start();
+        Some(token)
+    })
+}
+
+/// Reads the inside of `~cell(...)`: `i,j`, `i,"header"` or `B2`.
+fn cell_reference(token: &str, reference: &str) -> Result<Instruction> {
+    if let Some(cell) = CellRef::parse(reference.trim()) {
+        return Ok(Instruction::CellAt(cell.row, cell.col));
+    }
+    let (i, j) = reference.split_once(',').ok_or_else(|| {
+        eyre::eyre!(
+            "Invalid cell reference \"{token}\". Must be in the form '~cell(i,j)', '~cell(i,\"header\")' or '~cell(B2)'"
+        )
+    })?;
+    let i = i.trim().parse::<usize>()?;
+    let j = j.trim();
+    Ok(
+        match j.strip_prefix('"').and_then(|j| j.strip_suffix('"')) {
+            Some(header) => Instruction::CellAtHeader(i, header.to_string()),
+            None => Instruction::CellAt(i, j.parse::<usize>()?),
+        },
+    )
+}
+
+/// Postfix expression compiled once when the closure is defined.
+#[derive(Debug)]
+pub(crate) struct Postfix(Vec<Instruction>);
+
+impl FromStr for Postfix {
+    type Err = eyre::Report;
+
+    fn from_str(raw_input: &str) -> Result<Self> {
+        let raw_input = raw_input.trim();
+        let mut instructions = Vec::new();
+        let mut depth = 0;
+        let mut tokens = postfix_tokens(raw_input);
+        while let Some(token) = tokens.next() {
+            let instruction = if let Some(reference) = token.strip_prefix("~cell(") {
+                let reference = reference.strip_suffix(')').ok_or_else(|| {
+                    eyre::eyre!("Invalid cell reference \"{token}\", missing ')'.")
+                })?;
+                cell_reference(token, reference)?
+            } else if let Some(literal) = token
+                .strip_prefix('"')
+                .and_then(|token| token.strip_suffix('"'))
+            {
+                Instruction::Push(Cell::String(literal.to_string()))
+            } else {
+                match token {
+                    "~op" => Instruction::Storage,
+                    "~cell" => Instruction::CurrentCell,
+                    "~cell.x" => Instruction::CurrentRow,
+                    "~cell.y" => Instruction::CurrentColumn,
+                    "~fn" => {
+                        let name = tokens.next().ok_or_else(|| {
+                            suggestion!("Use '~fn closure_name' to call another closure.");
+                            eyre::eyre!("Closure name not specified after ~fn.")
+                        })?;
+                        Instruction::Call(name.to_string())
+                    }
+                    "?" => Instruction::Select,
+                    unknown if unknown.starts_with('~') => {
+                        suggestion!("Use one of ~op, ~cell, ~cell(i,j), ~cell.x, ~cell.y or ~fn.");
+                        bail!("Unknown reference \"{unknown}\" in \"{raw_input}\".")
+                    }
+                    _ => match token.parse::<Operation>() {
+                        Ok(op) => Instruction::Apply(op),
+                        Err(_) => Instruction::Push(Cell::from_str(token)?),
+                    },
+                }
+            };
+            let (pops, pushes) = instruction.arity();
+            if depth < pops {
+                bail!("Invalid postfix expression \"{raw_input}\". \"{token}\" needs {pops} operands but only {depth} are available.");
+            }
+            depth = depth - pops + pushes;
+            instructions.push(instruction);
+        }
+        if depth > 1 {
+            bail!("Invalid postfix expression \"{raw_input}\". Too many values.");
+        }
+        Ok(Postfix(instructions))
+    }
+}
+
+/// Expression slot of a script, either a postfix expression or `if!condition!then!else`.
+#[derive(Debug)]
+pub(crate) enum Expression {
+    Postfix(Postfix),
+    Conditional {
+        condition: Postfix,
+        then: Postfix,
+        otherwise: Postfix,
+    },
+}
+
+impl Expression {
+    fn read<'s>(reader: &mut impl Iterator<Item = &'s str>) -> Result<Self> {
+        let mut next = |what: &str| {
+            reader
+                .next()
+                .ok_or_else(|| eyre::eyre!("Invalid closure. No {what} specified."))
+        };
+        let expression = next("closure")?;
+        if expression.trim() != "if" {
+            return Ok(Expression::Postfix(expression.parse()?));
+        }
+        Ok(Expression::Conditional {
+            condition: next("condition for if")?.parse()?,
+            then: next("then branch for if")?.parse()?,
+            otherwise: next("else branch for if")?.parse()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum OutputCell {
+    /// Cell right below the end of the range.
+    Auto,
+    /// Row and column computed by postfix expressions.
+    At(Postfix, Postfix),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Prescript {
+    pub(crate) output: Option<OutputCell>,
+    pub(crate) registers: usize,
+}
+
+impl FromStr for Prescript {
+    type Err = eyre::Report;
+
+    fn from_str(prescript: &str) -> Result<Self> {
+        let mut compiled = Prescript::default();
+        let mut reader = split_script(prescript);
+        while let Some(token) = reader.next() {
+            match token.trim() {
+                "" => {}
+                "auto" => compiled.output = Some(OutputCell::Auto),
+                "none" => compiled.output = None,
+                "reg" => {
+                    compiled.registers = reader
+                        .next()
+                        .ok_or_else(|| {
+                            eyre::eyre!("Register count not specified. Must be a positive integer.")
+                        })?
+                        .trim()
+                        .parse::<usize>()?
+                }
+                cell if CellRef::parse(cell).is_some() => {
+                    let cell = CellRef::parse(cell).unwrap();
+                    let constant =
+                        |index: usize| Postfix(vec![Instruction::Push(Cell::Number(index as f64))]);
+                    compiled.output = Some(OutputCell::At(constant(cell.row), constant(cell.col)));
+                }
+                pair => {
+                    let (acc_i, acc_j) = pair.split_once(',').ok_or_else(|| {
+                        eyre::eyre!("Unknown prescript token \"{pair}\", expected 'auto', 'none', 'reg!count', a 'row,col' pair or a cell such as B3.")
+                    })?;
+                    compiled.output = Some(OutputCell::At(acc_i.parse()?, acc_j.parse()?));
+                }
+            }
+        }
+        Ok(compiled)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Storage {
+    Accumulator,
+    Register(usize),
+}
+
+#[derive(Debug)]
+pub(crate) enum ScriptMode {
+    /// Runs the expression once for every cell in range.
+    Each,
+    /// Runs the expression once for the whole range.
+    Raw,
+}
+
+#[derive(Debug)]
+pub(crate) struct Script {
+    pub(crate) mode: ScriptMode,
+    pub(crate) storage: Storage,
+    pub(crate) expression: Expression,
+}
+
+impl Script {
+    fn compile(script: &str, prescript: &Prescript) -> Result<Self> {
+        let mut reader = split_script(script);
+        let mode = match reader.next().unwrap_or_default().trim() {
+            "each" => ScriptMode::Each,
+            "raw" => ScriptMode::Raw,
+            "" => bail!("The script is empty, it must start with 'each' or 'raw'."),
+            unknown => bail!("The script must start with 'each' or 'raw', found \"{unknown}\"."),
+        };
+        let storage = match reader.next().map(str::trim) {
+            Some("acc") if prescript.output.is_none() => {
+                suggestion!("Declare an output cell with 'auto' or 'row,col' in the prescript.");
+                bail!("The script stores into 'acc' but the prescript declares no accumulator.")
+            }
+            Some("acc") => Storage::Accumulator,
+            Some("reg") => {
+                let reg_id = reader
+                    .next()
+                    .ok_or_else(|| {
+                        suggestion!("Use a number to specify a register.");
+                        eyre::eyre!("Register ID not specified.")
+                    })?
+                    .trim()
+                    .parse::<usize>()?;
+                if reg_id >= prescript.registers {
+                    suggestion!(
+                        "Declare registers in the prescript with 'reg!{}'.",
+                        reg_id + 1
+                    );
+                    bail!(
+                        "The script uses register {reg_id} but the prescript declares {} register(s).",
+                        prescript.registers
+                    );
+                }
+                Storage::Register(reg_id)
+            }
+            Some(unknown) => {
+                suggestion!("Use 'acc' or 'reg' to specify a storage location.");
+                bail!("Invalid storage location \"{unknown}\".")
+            }
+            None => {
+                suggestion!("Use 'acc' or 'reg' to specify a storage location.");
+                bail!("Storage location not specified.")
+            }
+        };
+        let expression = Expression::read(&mut reader)?;
+        if let Some(extra) = reader.next() {
+            bail!("Unexpected \"{extra}\" after the expression of the script.");
+        }
+        Ok(Script {
+            mode,
+            storage,
+            expression,
+        })
+    }
+}
+
+/// Closures written in Rust and preloaded into every `VM`, named as they are called.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Builtin {
+    /// `sum`, `count`, `avg`, `min` and `max`, computed as in formulas and `group_by`.
+    Aggregate(Function),
+    Median,
+    Stddev,
+    Product,
+    Concat,
+}
+
+impl Builtin {
+    pub(crate) const ALL: [Builtin; 9] = [
+        Builtin::Aggregate(Function::Sum),
+        Builtin::Aggregate(Function::Count),
+        Builtin::Aggregate(Function::Avg),
+        Builtin::Aggregate(Function::Min),
+        Builtin::Aggregate(Function::Max),
+        Builtin::Median,
+        Builtin::Stddev,
+        Builtin::Product,
+        Builtin::Concat,
+    ];
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Builtin::Aggregate(function) => function.name(),
+            Builtin::Median => "median",
+            Builtin::Stddev => "stddev",
+            Builtin::Product => "product",
+            Builtin::Concat => "concat",
+        }
+    }
+    /// Reduces the cells of a range. `count` counts the non-empty cells and `concat` joins
+    /// their text, the others use the numbers (also numeric text) and skip everything else.
+    fn compute(&self, cells: &[&Cell]) -> Result<Cell> {
+        let numbers: Vec<f64> = cells.iter().filter_map(|cell| cell.numeric()).collect();
+        let too_few = |count: usize| {
+            eyre!(
+                "{} needs at least {} in range, found {}.",
+                self.name(),
+                if count == 1 {
+                    "one number"
+                } else {
+                    "two numbers"
+                },
+                numbers.len()
+            )
+        };
+        let needs = |count: usize| {
+            if numbers.len() < count {
+                return Err(too_few(count));
+            }
+            Ok(())
+        };
+        Ok(match self {
+            Builtin::Aggregate(function) => Cell::Number(
+                function
+                    .aggregate(cells.iter().copied())
+                    .ok_or_else(|| too_few(1))?,
+            ),
+            Builtin::Median => {
+                needs(1)?;
+                let mut sorted = numbers;
+                sorted.sort_by(f64::total_cmp);
+                let mid = sorted.len() / 2;
+                Cell::Number(if sorted.len().is_multiple_of(2) {
+                    (sorted[mid - 1] + sorted[mid]) / 2_f64
+                } else {
+                    sorted[mid]
+                })
+            }
+            Builtin::Stddev => {
+                // sample standard deviation, as STDEV in a spreadsheet
+                needs(2)?;
+                let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+                let squares: f64 = numbers.iter().map(|n| (n - mean).powi(2)).sum();
+                Cell::Number((squares / (numbers.len() - 1) as f64).sqrt())
+            }
+            Builtin::Product => {
+                needs(1)?;
+                Cell::Number(numbers.iter().product())
+            }
+            Builtin::Concat => Cell::String(
+                cells
+                    .iter()
+                    .filter(|cell| ***cell != Cell::Empty)
+                    .map(|cell| cell.to_string())
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// What a closure runs over its range.
+#[derive(Debug)]
+pub(crate) enum Body {
+    Script(Script),
+    Native(Builtin),
+}
+
+/// Closures reachable from `~fn`, resolved by name through `VM.closure_idx`.
+pub(crate) struct ClosureScope<'a> {
+    pub(crate) closures: &'a [TableClosure],
+    pub(crate) closure_idx: &'a HashMap<String, usize>,
+}
+
+/// Deepest chain of `~fn` calls allowed before giving up.
+const MAX_CALL_DEPTH: usize = 32;
+
 pub(crate) struct FunctionExecutor<'a> {
     closure: &'a TableClosure,
     pub(crate) registers: Vec<Cell>,
     pub(crate) accumulator: Option<(usize, usize)>,
+    /// Names of the closures being executed, outermost first.
+    call_stack: Vec<&'a str>,
 }
 impl TableClosure {
-    pub(crate) fn new(mut defination: String) -> Result<Self> {
+    /// Compiles `prescript;script;postscript`, where the prescript and postscript may be
+    /// left out. Malformed closures are rejected here instead of when they are applied.
+    pub(crate) fn new(name: String, defination: String) -> Result<Self> {
         let components = defination.split(';').collect::<Vec<_>>();
-        let postscript = components[2].to_string();
-        let script = components[1].to_string();
-        defination.truncate(components[0].len());
+        let (prescript, script, postscript) = match components.as_slice() {
+            [script] => ("", *script, ""),
+            [prescript, script] => (*prescript, *script, ""),
+            [prescript, script, postscript] => (*prescript, *script, *postscript),
+            _ => {
+                suggestion!("A closure is written as \x1b[96mprescript;script;postscript\x1b[0m.");
+                bail!(
+                    "Closure {name} has {} sections separated by ';', at most 3 are allowed.",
+                    components.len()
+                )
+            }
+        };
+        let compile = || -> Result<(Prescript, Script)> {
+            let prescript = prescript.parse::<Prescript>()?;
+            let script = Script::compile(script, &prescript)?;
+            Ok((prescript, script))
+        };
+        let (prescript, script) =
+            compile().map_err(|e| eyre::eyre!("Closure {name} is malformed. {e}"))?;
         Ok(Self {
-            prescript: defination,
-            script,
-            postscript,
+            name,
+            prescript,
+            body: Body::Script(script),
+            postscript: postscript.trim().to_string(),
         })
     }
+    /// Built-in closure writing its result below the range, like a closure defined with
+    /// `auto`.
+    pub(crate) fn native(builtin: Builtin) -> Self {
+        Self {
+            name: builtin.name().to_string(),
+            prescript: Prescript {
+                output: Some(OutputCell::Auto),
+                registers: 0,
+            },
+            body: Body::Native(builtin),
+            postscript: String::new(),
+        }
+    }
+    pub(crate) fn is_native(&self) -> bool {
+        matches!(self.body, Body::Native(_))
+    }
     pub fn apply(
         &self,
         table: &mut Table,
         cell_start: (usize, usize),
         cell_end: (usize, usize),
-        table_closures: &Vec<TableClosure>,
+        scope: &ClosureScope,
     ) -> Result<()> {
         FunctionExecutor {
             closure: self,
             registers: Vec::new(),
             accumulator: None,
+            call_stack: vec![&self.name],
         }
-        .apply(table, cell_start, cell_end, table_closures)
+        .apply(table, cell_start, cell_end, scope)
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct TableClosure {
-    pub(crate) prescript: String,
-    pub(crate) script: String,
+    pub(crate) name: String,
+    pub(crate) prescript: Prescript,
+    pub(crate) body: Body,
     pub(crate) postscript: String,
 }
-impl FunctionExecutor<'_> {
+impl<'a> FunctionExecutor<'a> {
+    /// Evaluates a compiled postfix expression. `range` is the range the expression works
+    /// on, the current cell is its start and `~fn` calls run over all of it.
     pub(crate) fn do_artihmetic(
         &self,
-        raw_input: &str,
+        expression: &Postfix,
         storage_cell: &Cell,
-        (curr_i, curr_j): (usize, usize),
+        range: ((usize, usize), (usize, usize)),
         table: &Table,
-        vm: &Vec<TableClosure>,
+        scope: &ClosureScope<'a>,
     ) -> Result<Cell> {
+        let (curr_i, curr_j) = range.0;
         let mut stack: Vec<Cell> = Vec::new();
-        let raw_input = raw_input.trim();
-        // this will be in postfix notation
-        let mut raw_input = raw_input.split_whitespace();
-        while let Some(token) = raw_input.next() {
-            // todo: allow to do arthmetic on cells indexes
-            // by recursively calling do_arthmetic
-            // find a way to parse this
-            // suggestion: use a closed bracket detection
-            if token.starts_with("~cell(") {
-                let token = token
-                    .strip_prefix("~cell(")
-                    .unwrap()
-                    .strip_suffix(')')
-                    .ok_or_else(|| eyre::eyre!("Invalid cell reference."))?;
-                let (i, j) = token.split_once(',').ok_or_else(|| {
-                    eyre::eyre!("Invalid cell reference. Must be in the form '~cell(i,j)'")
-                })?;
-                let i = i.parse::<usize>()?;
-                let j = j.parse::<usize>()?;
-                stack.push(table.get_cell(i, j)?.clone());
-                continue;
-            }
-            match token {
-                "~op" => stack.push(storage_cell.clone()),
-                "~cell" => stack.push(table.get_cell(curr_i, curr_j)?.clone()),
-                "~cell.x" => stack.push(Cell::Number(curr_i as f64)),
-                "~cell.y" => stack.push(Cell::Number(curr_j as f64)),
-                "~fn" => {
-                    let fn_token = raw_input.next();
+        for instruction in &expression.0 {
+            let mut pop = || {
+                stack
+                    .pop()
+                    .ok_or_else(|| eyre::eyre!("Invalid postfix expression. Not enough operands."))
+            };
+            let value = match instruction {
+                Instruction::Push(cell) => cell.clone(),
+                Instruction::Storage => storage_cell.clone(),
+                Instruction::CurrentCell => table.get_cell(curr_i, curr_j)?.value().clone(),
+                Instruction::CellAt(i, j) => table.get_cell(*i, *j)?.value().clone(),
+                Instruction::CellAtHeader(i, header) => {
+                    let j = table.header_index(header).ok_or_else(|| {
+                        eyre::eyre!(
+                            "No column named \"{header}\" in table {}.",
+                            table.identifier()
+                        )
+                    })?;
+                    table.get_cell(*i, j)?.value().clone()
                 }
-                _ => {
-                    if let Ok(op) = token.parse::<Operation>() {
-                        let b = stack.pop().ok_or_else(|| {
-                            eyre::eyre!("Invalid postfix expression. Not enough operands.")
-                        })?;
-                        let a = stack.pop().ok_or_else(|| {
-                            eyre::eyre!("Invalid postfix expression. Not enough operands.")
-                        })?;
-                        stack.push(op.apply(a, b)?);
+                Instruction::CurrentRow => Cell::Number(curr_i as f64),
+                Instruction::CurrentColumn => Cell::Number(curr_j as f64),
+                Instruction::Call(name) => self.call(name, range, table, scope)?,
+                Instruction::Select => {
+                    let otherwise = pop()?;
+                    let then = pop()?;
+                    if pop()?.is_truthy() {
+                        then
                     } else {
-                        stack.push(Cell::from_str(token)?);
+                        otherwise
                     }
                 }
+                Instruction::Apply(op) => {
+                    let b = pop()?;
+                    let a = pop()?;
+                    op.apply(a, b)?
+                }
             };
-        }
-        if stack.len() > 1 {
-            bail!("Invalid prefix expression. Too many values.");
+            stack.push(value);
         }
         Ok(stack.pop().unwrap_or(Cell::Empty))
     }
+    /// Runs the closure `name` over `range` without touching the table and returns the
+    /// value it would have stored.
+    fn call(
+        &self,
+        name: &str,
+        (cell_start, cell_end): ((usize, usize), (usize, usize)),
+        table: &Table,
+        scope: &ClosureScope<'a>,
+    ) -> Result<Cell> {
+        if self.call_stack.contains(&name) {
+            bail!(
+                "Closure cycle detected: {} -> {name}",
+                self.call_stack.join(" -> ")
+            );
+        }
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            bail!("Closure calls are nested deeper than {MAX_CALL_DEPTH} levels.");
+        }
+        let &closure_idx = scope.closure_idx.get(name).ok_or_else(|| {
+            suggestion!("Closures must be defined before they are called with ~fn.");
+            eyre::eyre!("No closure found with name {name}.")
+        })?;
+        let closure = &scope.closures[closure_idx];
+        let mut call_stack = self.call_stack.clone();
+        call_stack.push(&closure.name);
+        let mut executor = FunctionExecutor {
+            closure,
+            registers: vec![Cell::Empty; closure.prescript.registers],
+            accumulator: None,
+            call_stack,
+        };
+        executor.run_script(table, cell_start, cell_end, scope)
+    }
+    fn evaluate(
+        &self,
+        expression: &Expression,
+        storage_cell: &Cell,
+        range: ((usize, usize), (usize, usize)),
+        table: &Table,
+        scope: &ClosureScope<'a>,
+    ) -> Result<Cell> {
+        let run = |postfix| self.do_artihmetic(postfix, storage_cell, range, table, scope);
+        match expression {
+            Expression::Postfix(postfix) => run(postfix),
+            Expression::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                if run(condition)?.is_truthy() {
+                    run(then)
+                } else {
+                    run(otherwise)
+                }
+            }
+        }
+    }
     pub(crate) fn apply(
         &mut self,
         table: &mut Table,
         cell_start: (usize, usize),
         cell_end: (usize, usize),
-        table_closures: &Vec<TableClosure>,
+        scope: &ClosureScope<'a>,
     ) -> Result<()> {
         let dimensions = table.dimensions();
         if dimensions < cell_start || dimensions < cell_end {
             bail!("Cell index out of range. Dimensions: {dimensions:?}, Cell start: {cell_start:?}, Cell end: {cell_end:?}");
         }
-        self.apply_prescript(table, cell_start, cell_end, table_closures)?;
-        let script = &self.closure.script;
-        let mut reader = script.split('!');
-        while let Some(token) = reader.next() {
-            let token = token.trim();
-            let mut get_storage_cell = || -> Result<Cell> {
-                let storage = reader.next().ok_or_else(|| {
-                    suggestion!("Use 'acc' or 'reg' to specify a storage location.");
-                    eyre::eyre!("Storage location not specified.")
-                })?;
-                let storage = storage.trim();
-                // right now storage type is redundant
-                let storage_type: StorageType;
-                Ok(match storage {
-                    "acc" => {
-                        let Some(acc) = self.accumulator else {
-                             bail!("Accumulator was not defined in the prescript.");
-                            };
-                        storage_type = StorageType::Accumulator(acc.0, acc.1);
-                        let storage_cell = std::mem::take(table.get_cell_mut(acc.0, acc.1)?);
-                        storage_cell
-                    }
-                    "reg" => {
-                        let reg_id = reader
-                            .next()
-                            .ok_or_else(|| {
-                                suggestion!("Use a number to specify a register.");
-                                eyre::eyre!("Register ID not specified.")
-                            })?
-                            .parse::<usize>()?;
-                        storage_type = StorageType::Register(reg_id);
-                        let reg_len = self.registers.len();
-                        std::mem::take(self.registers.get_mut(reg_id).ok_or_else(|| {
-                            eyre::eyre!(
-                                "Register ID out of range. (index is {} but length is {})",
-                                reg_id,
-                                reg_len
-                            )
-                        })?)
-                    }
-                    _ => {
-                        suggestion!("Use 'acc' or 'reg' to specify a storage location.");
-                        bail!("Invalid storage location.");
-                    }
-                })
-            };
-            match token {
-                "each" => {
-                    let mut storage_cell = get_storage_cell()?;
-                    let closure = reader
-                        .next()
-                        .ok_or_else(|| eyre::eyre!("Invalid closure. No closure specified."))?;
-                    for cell_i in (cell_start.0)..=cell_end.0 {
-                        for cell_j in (cell_start.1)..=cell_end.1 {
-                            storage_cell = self.do_artihmetic(
-                                closure,
-                                &storage_cell,
-                                (cell_i, cell_j),
-                                table,
-                                table_closures,
-                            )?;
-                        }
-                    }
-                    /* match storage_type {
-                        StorageType::Accumulator(x, y) => {
-                            std::mem::replace(table.get_cell_mut(x, y)?, storage_cell);
-                        }
-                        StorageType::Register(reg_id) => {
-                            self.registers[reg_id] = storage_cell;
-                        }
-                    } */
-                    if let Some((x, y)) = self.accumulator {
-                        *table.get_cell_mut(x, y)? = storage_cell;
+        self.apply_prescript(table, cell_start, cell_end, scope)?;
+        let storage_cell = self.run_script(table, cell_start, cell_end, scope)?;
+        if let Some((x, y)) = self.accumulator {
+            *table.get_cell_mut(x, y)? = storage_cell;
+        }
+        self.apply_postscript()
+    }
+    /// Runs the script over the range and returns the final value of its storage cell.
+    fn run_script(
+        &mut self,
+        table: &Table,
+        cell_start: (usize, usize),
+        cell_end: (usize, usize),
+        scope: &ClosureScope<'a>,
+    ) -> Result<Cell> {
+        let script = match &self.closure.body {
+            Body::Script(script) => script,
+            Body::Native(builtin) => {
+                let mut cells = Vec::new();
+                for row in cell_start.0..=cell_end.0 {
+                    for col in cell_start.1..=cell_end.1 {
+                        cells.push(table.get_cell(row, col)?.value());
                     }
-                    return self.apply_postscript(table, cell_start, cell_end);
                 }
-                "raw" => {
-                    let mut storage_cell = get_storage_cell()?;
-                    let closure = reader
-                        .next()
-                        .ok_or_else(|| eyre::eyre!("Invalid closure. No closure specified."))?;
-                    storage_cell = self.do_artihmetic(
-                        closure,
-                        &storage_cell,
-                        (cell_start.0, cell_start.0),
-                        table,
-                        table_closures,
-                    )?;
-                    if let Some((x, y)) = self.accumulator {
-                        *table.get_cell_mut(x, y)? = storage_cell;
+                return builtin.compute(&cells);
+            }
+        };
+        let mut storage_cell = match script.storage {
+            Storage::Accumulator => match self.accumulator {
+                Some(acc) => table.get_cell(acc.0, acc.1)?.value().clone(),
+                // closures called through ~fn have no output cell
+                None => Cell::Empty,
+            },
+            Storage::Register(reg_id) => {
+                let reg_len = self.registers.len();
+                std::mem::take(self.registers.get_mut(reg_id).ok_or_else(|| {
+                    eyre::eyre!(
+                        "Register ID out of range. (index is {} but length is {})",
+                        reg_id,
+                        reg_len
+                    )
+                })?)
+            }
+        };
+        match script.mode {
+            ScriptMode::Each => {
+                for cell_i in (cell_start.0)..=cell_end.0 {
+                    for cell_j in (cell_start.1)..=cell_end.1 {
+                        storage_cell = self.evaluate(
+                            &script.expression,
+                            &storage_cell,
+                            ((cell_i, cell_j), (cell_i, cell_j)),
+                            table,
+                            scope,
+                        )?;
                     }
-                    return self.apply_postscript(table, cell_start, cell_end);
                 }
-                /* "acc" => {}
-                "reg" => {}
-                "mem" => {}
-                "if" => {} */
-                _ => bail!("Invalid token in closure."),
+                Ok(storage_cell)
             }
+            ScriptMode::Raw => self.evaluate(
+                &script.expression,
+                &storage_cell,
+                (cell_start, cell_end),
+                table,
+                scope,
+            ),
         }
-
-        Ok(())
     }
-    pub(crate) fn apply_postscript(
-        &mut self,
-        table: &mut Table,
-        cell_start: (usize, usize),
-        cell_end: (usize, usize),
-    ) -> Result<()> {
+    pub(crate) fn apply_postscript(&mut self) -> Result<()> {
+        if !self.closure.postscript.is_empty() {
+            warn!("Postscript is not supported yet, ignoring it.");
+        }
         self.destroy();
         Ok(())
     }
+    /// Sets up the accumulator and registers declared in the prescript.
     pub(crate) fn apply_prescript(
         &mut self,
         table: &mut Table,
         cell_start: (usize, usize),
         cell_end: (usize, usize),
-        table_closures: &Vec<TableClosure>,
+        scope: &ClosureScope<'a>,
     ) -> Result<()> {
-        let script = &self.closure.prescript;
-        let mut reader = script.split('!');
-        // defines accumulator, registers, and memory
-        while let Some(acc) = reader.next() {
-            let acc = acc.trim();
-            match acc {
-                "auto" => {
-                    let output_cell = (cell_end.0 + 1, cell_end.1);
-                    self.accumulator = Some(output_cell);
-                    if table.dimensions() < (output_cell.0 + 1, output_cell.1 + 1) {
-                        table.create_empty_row();
-                    }
+        let prescript = &self.closure.prescript;
+        self.registers = vec![Cell::Empty; prescript.registers];
+        match &prescript.output {
+            None => self.accumulator = None,
+            Some(OutputCell::Auto) => {
+                let output_cell = (cell_end.0 + 1, cell_end.1);
+                self.accumulator = Some(output_cell);
+                if table.dimensions() < (output_cell.0 + 1, output_cell.1 + 1) {
+                    table.create_empty_row();
                 }
-                "reg" => {
-                    let reg_count = reader
-                        .next()
-                        .ok_or_else(|| {
-                            eyre::eyre!("Register count not specified. Must be a positive integer.")
-                        })?
-                        .parse::<usize>()?;
-                    self.registers = vec![Cell::Empty; reg_count];
-                }
-                _ => {
-                    let (acc_i, acc_j) = acc.split_once(',').ok_or_else(|| {
-                        eyre::eyre!("Accumulator defined incorrectly. Must be 'auto' or 'none' or a accepted comma sepated index pair.")
-                    })?;
-                    let temp_cell = Cell::Empty;
-                    let acc_i = self.do_artihmetic(
-                        acc_i,
-                        &temp_cell,
-                        (cell_end.0 + 1, cell_end.1),
-                        table,
-                        table_closures,
-                    )?;
-                    let acc_j = self.do_artihmetic(
-                        acc_j,
-                        &temp_cell,
-                        (cell_start.0, cell_start.1),
-                        table,
-                        table_closures,
-                    )?;
-                    let Cell::Number(x) = acc_i else {
-                        bail!("Accumulator defined incorrectly. Must be 'auto' or 'none' or a accepted comma sepated index pair.")
-                    };
-                    let Cell::Number(y) = acc_j else {
-                        bail!("Accumulator defined incorrectly. Must be 'auto' or 'none' or a accepted comma sepated index pair.")
-                    };
+            }
+            Some(OutputCell::At(acc_i, acc_j)) => {
+                let temp_cell = Cell::Empty;
+                let acc_i_pos = (cell_end.0 + 1, cell_end.1);
+                let acc_i =
+                    self.do_artihmetic(acc_i, &temp_cell, (acc_i_pos, acc_i_pos), table, scope)?;
+                let acc_j =
+                    self.do_artihmetic(acc_j, &temp_cell, (cell_start, cell_start), table, scope)?;
+                let Cell::Number(x) = acc_i else {
+                    bail!("Accumulator defined incorrectly. Must be 'auto' or 'none' or a accepted comma sepated index pair.")
+                };
+                let y = match acc_j {
+                    Cell::Number(y) => y,
+                    // `row,"header"` names the column
+                    Cell::String(header) => table.header_index(&header).ok_or_else(|| {
+                        eyre::eyre!("No column named \"{header}\" in table {}.", table.identifier())
+                    })? as f64,
+                    _ => bail!("Accumulator defined incorrectly. Must be 'auto' or 'none' or a accepted comma sepated index pair."),
+                };
 
-                    let output_cell = (x.ceil() as usize, y.ceil() as usize);
-                    self.accumulator = Some(output_cell);
-                    while table.dimensions().0 < output_cell.0 {
-                        table.create_empty_row();
-                    }
+                let output_cell = (x.ceil() as usize, y.ceil() as usize);
+                self.accumulator = Some(output_cell);
+                while table.dimensions().0 < output_cell.0 {
+                    table.create_empty_row();
                 }
             }
         }
-
         Ok(())
     }
     pub(crate) fn destroy(&mut self) {
@@ -1,75 +1,115 @@
 pub use crate::table::*;
-use crate::{autocorrect::keyboard_distance_matcher, closure::TableClosure, suggestion, warn};
-use chatgpt::types::CompletionResponse;
-use eyre::{bail, eyre, Result};
-use std::{
-    collections::HashMap,
-    fmt::Display,
-    fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
-    str::FromStr,
+use crate::{
+    autocorrect::keyboard_distance_matcher,
+    closure::{Builtin, ClosureScope, TableClosure},
+    config::Config,
+    formula::CellRef,
+    lexer::Span,
+    llm::{backend_from_config, CachedBackend, LlmBackend, CACHE_DIR, CHARS_PER_TOKEN},
+    parser::{ColumnRef, Ident, Parser, PlotAxis, Statement},
+    suggestion, warn,
 };
+use eyre::{bail, Result};
+use std::{collections::HashMap, fmt::Display, fs::File, io::Write, rc::Rc, str::FromStr};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ResponsePlotType {
     Bargraph((usize, usize)),
     Histogram(usize),
-    Piechart(usize),
+    /// Label column and value column, occurrences of each label are counted without one.
+    Piechart((usize, Option<usize>)),
+    Linegraph((usize, usize)),
     Scatterplot((usize, usize)),
     DataInsufficient,
     None,
 }
+impl ResponsePlotType {
+    /// Reads a `{"plot": ..., "x": ..., "y": ...}` object embedded anywhere in `reply`.
+    fn from_json(reply: &str) -> Option<ResponsePlotType> {
+        let start = reply.find('{')?;
+        let end = reply.rfind('}')?;
+        let value: serde_json::Value = serde_json::from_str(reply.get(start..=end)?).ok()?;
+        let kind = value.get("plot").or_else(|| value.get("type"))?.as_str()?;
+        let column = |axis: &str| {
+            let column = value.get(axis)?;
+            column
+                .as_u64()
+                .map(|n| n as usize)
+                .or_else(|| column.as_str()?.trim().parse().ok())
+        };
+        Self::from_parts(kind, column("x"), column("y")).ok()
+    }
+
+    /// Builds a plot from its name and 1-based column numbers, failing like an unknown name
+    /// when the plot is missing a column it needs.
+    fn from_parts(kind: &str, x: Option<usize>, y: Option<usize>) -> Result<ResponsePlotType, ()> {
+        let normalize = |word: &str| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        };
+        let x = x.and_then(|x| x.checked_sub(1));
+        let y = y.and_then(|y| y.checked_sub(1));
+        let pair = x.zip(y);
+        // the whole phrase first ("Data Insufficient"), then word by word ("Plot: Bargraph")
+        let candidates =
+            std::iter::once(normalize(kind)).chain(kind.split_whitespace().map(normalize));
+        for candidate in candidates {
+            let graph = match candidate.as_str() {
+                "bargraph" | "bar" | "barchart" => pair.map(ResponsePlotType::Bargraph),
+                "scatterplot" | "scatter" => pair.map(ResponsePlotType::Scatterplot),
+                "linegraph" | "line" | "linechart" => pair.map(ResponsePlotType::Linegraph),
+                "histogram" | "hist" => x.map(ResponsePlotType::Histogram),
+                "piechart" | "pie" => x.map(|x| ResponsePlotType::Piechart((x, y))),
+                "datainsufficient" | "insufficientdata" | "insufficient" => {
+                    Some(ResponsePlotType::DataInsufficient)
+                }
+                "none" => Some(ResponsePlotType::None),
+                _ => continue,
+            };
+            return graph.ok_or(());
+        }
+        Err(())
+    }
+
+    /// Whether every column the plot uses exists in a table with `columns` columns.
+    pub fn fits(&self, columns: usize) -> bool {
+        match *self {
+            ResponsePlotType::Bargraph((x, y))
+            | ResponsePlotType::Scatterplot((x, y))
+            | ResponsePlotType::Linegraph((x, y)) => x < columns && y < columns,
+            ResponsePlotType::Histogram(x) => x < columns,
+            ResponsePlotType::Piechart((x, y)) => x < columns && y.is_none_or(|y| y < columns),
+            ResponsePlotType::DataInsufficient | ResponsePlotType::None => true,
+        }
+    }
+}
+
 impl FromStr for ResponsePlotType {
     type Err = ();
 
+    /// Reads a model reply, either as JSON or as the plot name followed by `x = N` and `y = N`
+    /// lines. Column numbers in the reply are 1-based.
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let mut text = s.lines().map(|s| s.trim());
-        if let Some(plot_type) = text.next() {
-            let mut read_tuple = || -> Option<(usize, usize)> {
-                let x = text.next()?.split_whitespace().last()?;
-                let y = text.next()?.split_whitespace().last()?;
-                Some((x.parse::<usize>().ok()? - 1, y.parse::<usize>().ok()? - 1))
+        if let Some(graph) = Self::from_json(s) {
+            return Ok(graph);
+        }
+        let mut lines = s.lines().map(str::trim).filter(|line| !line.is_empty());
+        let kind = lines.next().ok_or(())?;
+        let (mut x, mut y) = (None, None);
+        for line in lines {
+            let Some((axis, value)) = line.split_once(['=', ':']) else {
+                continue;
             };
-            match plot_type.to_lowercase().as_str() {
-                "bargraph" | "bar" => {
-                    let tuple = read_tuple();
-                    if let Some(tuple) = tuple {
-                        Ok(ResponsePlotType::Bargraph(tuple))
-                    } else {
-                        Ok(ResponsePlotType::DataInsufficient)
-                    }
-                }
-                "scatterplot" | "scatter" => {
-                    let tuple = read_tuple();
-                    if let Some(tuple) = tuple {
-                        Ok(ResponsePlotType::Bargraph(tuple))
-                    } else {
-                        Ok(ResponsePlotType::DataInsufficient)
-                    }
-                }
-                "histogram" | "hist" => {
-                    let token = text.next().ok_or(())?.split_whitespace().last().ok_or(())?;
-                    if let Some(idx) = token.parse::<usize>().ok() {
-                        Ok(ResponsePlotType::Histogram(idx))
-                    } else {
-                        Ok(ResponsePlotType::DataInsufficient)
-                    }
-                }
-                "piechart" | "pie" => {
-                    let token = text.next().ok_or(())?.split_whitespace().last().ok_or(())?;
-                    if let Some(idx) = token.parse::<usize>().ok() {
-                        Ok(ResponsePlotType::Piechart(idx))
-                    } else {
-                        Ok(ResponsePlotType::DataInsufficient)
-                    }
-                }
-                "data_insufficient" => Ok(ResponsePlotType::DataInsufficient),
-                "none" => Ok(ResponsePlotType::None),
-                _ => Err(()),
+            let value = value.trim().trim_end_matches([',', '.']).parse().ok();
+            match axis.trim().to_lowercase().as_str() {
+                "x" => x = value,
+                "y" => y = value,
+                _ => {}
             }
-        } else {
-            Err(())
         }
+        Self::from_parts(kind, x, y)
     }
 }
 
@@ -81,6 +121,27 @@ pub enum Operator {
     DefineClosure,
     View,
     Prompt,
+    Load,
+    Plot,
+    PromptInto,
+    FillColumn,
+    Set,
+    DeleteRow,
+    InsertRow,
+    UpdateRow,
+    AddColumn,
+    DropColumn,
+    RenameColumn,
+    MoveColumn,
+    ReorderColumns,
+    Sort,
+    Filter,
+    GroupBy,
+    Pivot,
+    Join,
+    LeftJoin,
+    OuterJoin,
+    Append,
 }
 
 pub(crate) enum Token<'a> {
@@ -90,9 +151,11 @@ pub(crate) enum Token<'a> {
     Table(&'a HashMap<String, usize>),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Modifier {
     WithHeader,
     WithTitle,
+    WithDelimiter,
 }
 
 impl Modifier {
@@ -106,6 +169,7 @@ impl FromStr for Modifier {
         match s {
             "header" | "headers" => Ok(Modifier::WithHeader),
             "title" => Ok(Modifier::WithTitle),
+            "delimiter" => Ok(Modifier::WithDelimiter),
             unknown => {
                 let correction = keyboard_distance_matcher(s, Token::DataModifier);
                 suggestion!("Did you mean to use \"{correction}\"?");
@@ -125,6 +189,27 @@ impl Display for Operator {
             Operator::View => write!(f, "view"),
             Operator::DefineClosure => write!(f, "define"),
             Operator::Prompt => write!(f, "prompt"),
+            Operator::Load => write!(f, "load"),
+            Operator::Plot => write!(f, "plot"),
+            Operator::PromptInto => write!(f, "prompt_into"),
+            Operator::FillColumn => write!(f, "fill_column"),
+            Operator::Set => write!(f, "set"),
+            Operator::DeleteRow => write!(f, "delete_row"),
+            Operator::InsertRow => write!(f, "insert_row"),
+            Operator::UpdateRow => write!(f, "update_row"),
+            Operator::AddColumn => write!(f, "add_column"),
+            Operator::DropColumn => write!(f, "drop_column"),
+            Operator::RenameColumn => write!(f, "rename_column"),
+            Operator::MoveColumn => write!(f, "move_column"),
+            Operator::ReorderColumns => write!(f, "reorder_columns"),
+            Operator::Sort => write!(f, "sort"),
+            Operator::Filter => write!(f, "filter"),
+            Operator::GroupBy => write!(f, "group_by"),
+            Operator::Pivot => write!(f, "pivot"),
+            Operator::Join => write!(f, "join"),
+            Operator::LeftJoin => write!(f, "left_join"),
+            Operator::OuterJoin => write!(f, "outer_join"),
+            Operator::Append => write!(f, "append"),
         }
     }
 }
@@ -140,6 +225,27 @@ impl FromStr for Operator {
             "view" => Operator::View,
             "define" => Operator::DefineClosure,
             "prompt" => Operator::Prompt,
+            "load" => Operator::Load,
+            "plot" => Operator::Plot,
+            "prompt_into" => Operator::PromptInto,
+            "fill_column" => Operator::FillColumn,
+            "set" => Operator::Set,
+            "delete_row" => Operator::DeleteRow,
+            "insert_row" => Operator::InsertRow,
+            "update_row" | "update" => Operator::UpdateRow,
+            "add_column" => Operator::AddColumn,
+            "drop_column" => Operator::DropColumn,
+            "rename_column" => Operator::RenameColumn,
+            "move_column" => Operator::MoveColumn,
+            "reorder_columns" | "reorder" => Operator::ReorderColumns,
+            "sort" => Operator::Sort,
+            "filter" | "where" => Operator::Filter,
+            "group_by" | "groupby" => Operator::GroupBy,
+            "pivot" => Operator::Pivot,
+            "join" | "inner_join" => Operator::Join,
+            "left_join" => Operator::LeftJoin,
+            "outer_join" | "full_join" => Operator::OuterJoin,
+            "append" => Operator::Append,
             unknown => {
                 let correction = keyboard_distance_matcher(s, Token::Operator);
                 suggestion!("Did you mean \x1b[96m{}\x1b[0m?", correction);
@@ -149,6 +255,9 @@ impl FromStr for Operator {
     }
 }
 
+/// Length of the fixed instructions wrapped around tables and the question in a prompt.
+const PROMPT_PREAMBLE_LEN: usize = 160;
+
 pub struct VM {
     pub(crate) code_text: String,
     pub(crate) tables: Vec<Table>,
@@ -156,54 +265,53 @@ pub struct VM {
     pub(crate) pointer: usize,
     pub(crate) closure_idx: HashMap<String, usize>,
     pub(crate) closures: Vec<TableClosure>,
+    pub(crate) config: Config,
+    /// Backend used instead of the configured one, set by tests and embedders.
+    pub(crate) llm: Option<Rc<dyn LlmBackend>>,
+    /// Whether responses of the configured backend are cached in `CACHE_DIR`.
+    pub(crate) use_cache: bool,
 }
 
 impl VM {
     pub fn new(code: String) -> VM {
+        let config = Config::load();
         VM {
             code_text: code,
             tables: Vec::new(),
             tables_idx: HashMap::new(),
             pointer: 0,
-            closure_idx: HashMap::new(),
-            closures: Vec::new(),
+            closure_idx: Builtin::ALL
+                .iter()
+                .enumerate()
+                .map(|(idx, builtin)| (builtin.name().to_string(), idx))
+                .collect(),
+            closures: Builtin::ALL.map(TableClosure::native).into(),
+            use_cache: config.llm_cache(),
+            config,
+            llm: None,
         }
     }
 
-    fn parse_double_quote(token: &mut std::str::SplitWhitespace<'_>) -> Result<String> {
-        let token_start = token
-            .next()
-            .ok_or_else(|| eyre!("String literal not found."))?;
-        if token_start.is_empty() || !token_start.starts_with('"') {
-            suggestion!("String literals must be enclosed in double quotes (\").");
-            return Err(eyre!(
-                "String literal not found, found \"{token_start}\" instead."
-            ));
-        }
-        if token_start.ends_with('"') && token_start.len() != 1 {
-            return Ok(String::from(&token_start[1..token_start.len() - 1]));
-        }
-        let mut string = String::from(&token_start[1..]);
-
-        while let Some(token) = token.next() {
-            string.push(' ');
-            if token.ends_with('"') {
-                string.push_str(&token[..token.len() - 1]);
-                return Ok(string);
-            } else {
-                string.push_str(token);
+    /// The LLM backend answering prompts and plot suggestions.
+    pub(crate) fn backend(&self) -> Result<Rc<dyn LlmBackend>> {
+        match &self.llm {
+            Some(llm) => Ok(llm.clone()),
+            None => {
+                let llm: Rc<dyn LlmBackend> = Rc::from(backend_from_config(&self.config)?);
+                if self.use_cache {
+                    Ok(Rc::new(CachedBackend::new(llm, CACHE_DIR)))
+                } else {
+                    Ok(llm)
+                }
             }
         }
-        warn!("String literal was not closed properly.");
-        suggestion!("Try adding a double quote (\") at the end of the string literal.");
-        Ok(string)
     }
 
     pub fn interpret(&mut self) -> Result<()> {
         while self.pointer < self.code_text.len() {
             self.interpret_next_line()?
         }
-        return Ok(());
+        Ok(())
     }
 
     pub fn interpret_next_line(&mut self) -> Result<()> {
@@ -211,349 +319,587 @@ impl VM {
             .find('\n')
             .unwrap_or(self.code_text.len() - self.pointer);
 
-        let code_line = self.code_text[self.pointer..self.pointer + code_line_end].trim();
+        let code_line = self.code_text[self.pointer..self.pointer + code_line_end]
+            .trim()
+            .to_string();
         self.pointer += code_line_end + 1;
         if code_line.is_empty() {
             return Ok(());
         }
 
-        let mut read_pointer = 0;
-        let mut token = code_line.split_whitespace();
-        let table_name_token = token
-            .next()
-            .ok_or_else(|| eyre!("{code_line}\n^\nNo token found referencing to table"))?;
-        if table_name_token == "render" {
-            // compiler intrinsic
-            let mut file = File::create("table.html")?;
-            file.write(b"<!DOCTYPE html><html><body><style>table,th,td{border:1px solid black;padding:3px;margin:2px;}</style>")?;
-            file.write_all(&self.save(OutputType::Html)?)?;
-            file.write(b"</body></html>")?;
-            println!("Render successful.");
-            return Ok(());
-        }
-        read_pointer += table_name_token.len() + 1;
+        let statement = Parser::new(&code_line).parse()?;
+        self.execute(&code_line, statement)
+    }
 
-        let operator = token.next().ok_or_else(|| {
-            let table_exists = self.tables_idx.get(table_name_token);
-            if let Some(&idx) = table_exists {
-                suggestion!(
-                    "Table {table_name} can be displayed with \x1b[96m{table_name_token} view\x1b[0m.",
-                    table_name = self.tables[idx].title()
-                )
-            } else {
-                suggestion!("Use \x1b[96m{table_name_token} create\x1b[0m to create a new table.");
-                 if !self.tables.is_empty() {
-                    let correction =
-                        keyboard_distance_matcher(table_name_token, Token::Table(&self.tables_idx));
-                    suggestion!("Table with name {correction} also exists.");
-                }
+    /// Creates an empty table with the given name, overwriting any existing one.
+    fn create_table(&mut self, table_name_token: &str) -> usize {
+        *self
+            .tables_idx
+            .entry(table_name_token.to_string())
+            .and_modify(|&mut old_table| {
+                warn!(
+                    "Table {table_name_token} ({title}) already existed, overwriting it.",
+                    table_name_token = self.tables[old_table].identifier(),
+                    title = self.tables[old_table].title()
+                );
+                self.tables[old_table] = Table::new(table_name_token.to_string());
+            })
+            .or_insert_with(|| {
+                self.tables.push(Table::new(table_name_token.to_string()));
+                self.tables.len() - 1
+            })
+    }
+
+    /// Resolves a table name to its index, suggesting close matches when it is unknown.
+    fn table_index(&self, code_line: &str, table: &Ident) -> Result<usize> {
+        self.tables_idx.get(&table.name).copied().ok_or_else(|| {
+            if !self.tables.is_empty() {
+                let correction =
+                    keyboard_distance_matcher(&table.name, Token::Table(&self.tables_idx));
+                suggestion!("Did you mean to refer \"{correction}\" ?");
             }
-            eyre!(
-                "{code_line}\n{carrot}\nNo token found for any operation",
-                carrot = String::from_iter(
-                    std::iter::repeat(' ')
-                        .take(read_pointer)
-                        .chain(std::iter::once('^'))
-                ),
-            )
-        })?;
-        let mut temp_read_pointer = read_pointer;
-        read_pointer += operator.len() + 1;
-        let operator = Operator::from_str(operator).map_err(|e| {
-            eyre!(
-                "{code_line}\n{carrot}\n{error}",
-                carrot = String::from_iter(
-                    std::iter::repeat(' ')
-                        .take(temp_read_pointer)
-                        .chain(std::iter::repeat('^').take(operator.len()))
-                ),
-                error = e,
+            table.span.report(
+                code_line,
+                format!("No table found with name \"{}\"", table.name),
             )
-        })?;
-        let table_idx = match operator {
-            Operator::DefineClosure => 0_usize,
-            Operator::Assignment => *self
-                .tables_idx
-                .entry(table_name_token.to_string())
-                .and_modify(|&mut old_table| {
-                    warn!(
-                        "Table {table_name_token} ({title}) already existed, overwriting it.",
-                        table_name_token = self.tables[old_table].identifier(),
-                        title = self.tables[old_table].title()
+        })
+    }
+
+    fn execute(&mut self, code_line: &str, statement: Statement) -> Result<()> {
+        match statement {
+            Statement::Render => {
+                // compiler intrinsic
+                let mut file = File::create("table.html")?;
+                file.write_all(b"<!DOCTYPE html><html><body><style>table,th,td{border:1px solid black;padding:3px;margin:2px;}</style>")?;
+                file.write_all(&self.save(OutputType::Html)?)?;
+                file.write_all(b"</body></html>")?;
+                println!("Render successful.");
+            }
+            Statement::ClearCache => {
+                let removed = CachedBackend::clear(std::path::Path::new(CACHE_DIR))?;
+                println!("Removed {removed} cached responses.");
+            }
+            Statement::Dangling { table } => {
+                let table_name_token = table.name.as_str();
+                if let Some(&idx) = self.tables_idx.get(table_name_token) {
+                    suggestion!(
+                        "Table {table_name} can be displayed with \x1b[96m{table_name_token} view\x1b[0m.",
+                        table_name = self.tables[idx].title()
+                    )
+                } else {
+                    suggestion!(
+                        "Use \x1b[96m{table_name_token} create\x1b[0m to create a new table."
                     );
-                    self.tables[old_table] = Table::new(table_name_token.to_string());
-                })
-                .or_insert_with(|| {
-                    self.tables.push(Table::new(table_name_token.to_string()));
-                    self.tables.len() - 1
-                }),
-            _ => *self.tables_idx.get(table_name_token).ok_or_else(|| {
-                if !self.tables.is_empty() {
-                    let correction =
-                        keyboard_distance_matcher(table_name_token, Token::Table(&self.tables_idx));
-                    suggestion!("Did you mean to refer \"{correction}\" ?");
+                    if !self.tables.is_empty() {
+                        let correction = keyboard_distance_matcher(
+                            table_name_token,
+                            Token::Table(&self.tables_idx),
+                        );
+                        suggestion!("Table with name {correction} also exists.");
+                    }
                 }
-                eyre!(
-                    "{code_line}\n{carrot}\nNo table found with name \"{table_name_token}\"",
-                    carrot = String::from_iter(std::iter::repeat('^').take(table_name_token.len())),
-                )
-            })?,
-        }
-        .clone();
-        match operator {
-            Operator::Assignment => {
-                // Table was already created, now dealing with modifiers
+                bail!(Span::point(table.span.end + 1)
+                    .report(code_line, "No token found for any operation"));
+            }
+            Statement::Create { table, modifiers } => {
+                let table_idx = self.create_table(&table.name);
                 let table = &mut self.tables[table_idx];
-
-                while let Some(modifier_token) = token.next() {
-                    temp_read_pointer = read_pointer;
-                    read_pointer += modifier_token.len() + 1;
-                    if Modifier::is_modifier(modifier_token) {
-                        let modifier_token = token.next().ok_or_else(|| {
-                            eyre!(
-                            "{code_line}\n{carrot}\nNo token found for modifier {modifier_token}.",
-                            carrot = String::from_iter(
-                                std::iter::repeat(' ')
-                                    .take(temp_read_pointer)
-                                    .chain(std::iter::repeat('^').take(modifier_token.len()))
-                            ),
-                        )
-                        })?;
-
-                        temp_read_pointer = read_pointer;
-                        read_pointer += modifier_token.len() + 1;
-                        let modifier = Modifier::from_str(modifier_token).map_err(|e| {
-                            eyre!(
-                                "{code_line}\n{carrot}\n{error}",
-                                carrot = String::from_iter(
-                                    std::iter::repeat(' ')
-                                        .take(temp_read_pointer)
-                                        .chain(std::iter::repeat('^').take(modifier_token.len()))
-                                ),
-                                error = e,
-                            )
-                        })?;
-
-                        let mut cell_data = Vec::new();
-                        while let Some(next_token) = token.clone().next() {
-                            if !next_token.starts_with('"') {
-                                break;
-                            }
-                            let cell_element =
-                                Self::parse_double_quote(&mut token).map_err(|e| {
-                                    eyre!(
-                                        "{code_line}\n{carrot}\n{error}",
-                                        carrot = String::from_iter(
-                                            std::iter::repeat(' ').take(read_pointer).chain(
-                                                std::iter::repeat('^').take(next_token.len())
-                                            )
-                                        ),
-                                        error = e,
-                                    )
-                                })?;
-                            read_pointer += cell_element.len() + 3;
-                            cell_data.push(cell_element);
-                        }
-
-                        if cell_data.is_empty() {
-                            bail!("{code_line}\n{carrot}\nNo data found for modifier {modifier_token}" ,carrot = String::from_iter(
-                                    std::iter::repeat(' ')
-                                        .take(temp_read_pointer)
-                                        .chain(std::iter::repeat('^').take(modifier_token.len()))
-                                ),);
-                        }
-                        match modifier {
-                            Modifier::WithHeader => table.extend_headers(
-                                cell_data
-                                    .into_iter()
-                                    .map(|s| Cell::from_str(&s))
-                                    .collect::<Result<Vec<_>>>()?,
-                            ),
-                            Modifier::WithTitle => table.assign_title(cell_data.swap_remove(0)),
-                        }
-                    } else {
-                        let correction = keyboard_distance_matcher(modifier_token, Token::Modifier);
-                        suggestion!("You may want to use \x1b[96m{correction}\x1b[0m instead.");
-                        warn!(
-                        "Expected modifier but found \"{modifier_token}\", ignoring token\n{code_line}\n{carrot}",
-                        carrot = String::from_iter(
-                            std::iter::repeat(' ')
-                                .take(temp_read_pointer)
-                                .chain(std::iter::repeat('^').take(modifier_token.len()))
+                for (modifier, mut cell_data) in modifiers {
+                    match modifier {
+                        Modifier::WithHeader => table.extend_headers(
+                            cell_data
+                                .into_iter()
+                                .map(|s| Cell::from_str(&s))
+                                .collect::<Result<Vec<_>>>()?,
                         ),
-                    );
+                        Modifier::WithTitle => table.assign_title(cell_data.swap_remove(0)),
+                        Modifier::WithDelimiter => {
+                            warn!("Delimiter is only used while loading a file, ignoring it.")
+                        }
                     }
                 }
             }
-            Operator::AddRow => {
-                let mut cell_data = Vec::new();
-                while let Some(next_token) = token.clone().next() {
-                    let cell_element = Self::parse_double_quote(&mut token).map_err(|e| {
-                        eyre!(
-                            "{code_line}\n{carrot}\n{error}",
-                            carrot = String::from_iter(
-                                std::iter::repeat(' ')
-                                    .take(read_pointer)
-                                    .chain(std::iter::repeat('^').take(next_token.len()))
-                            ),
-                            error = e,
-                        )
-                    })?;
-                    read_pointer += cell_element.len() + 3;
-                    cell_data.push(cell_element);
+            Statement::Load {
+                table,
+                path,
+                modifiers,
+            } => {
+                let mut has_headers = false;
+                let mut delimiter = ',';
+                let mut title = None;
+                for (modifier, mut cell_data) in modifiers {
+                    match modifier {
+                        Modifier::WithHeader => has_headers = true,
+                        Modifier::WithTitle => title = Some(cell_data.swap_remove(0)),
+                        Modifier::WithDelimiter => {
+                            let mut chars = cell_data[0].chars();
+                            delimiter = match (chars.next(), chars.next()) {
+                                (Some(c), None) => c,
+                                _ => bail!(
+                                    "Delimiter must be a single character, found \"{}\".",
+                                    cell_data[0]
+                                ),
+                            };
+                        }
+                    }
                 }
-
-                let cell_data = cell_data.iter().map(AsRef::as_ref).collect();
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|e| eyre::eyre!("Could not read \"{path}\": {e}"))?;
+                let records = parse_csv(&text, delimiter)?;
+                let table_idx = self.create_table(&table.name);
+                let table = &mut self.tables[table_idx];
+                table.load_records(records, has_headers);
+                if let Some(title) = title {
+                    table.assign_title(title);
+                }
+                table.recalculate()?;
+            }
+            Statement::AddRow { table, cells } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let cell_data = cells.iter().map(AsRef::as_ref).collect();
                 self.tables[table_idx].append_row_from(cell_data);
+                self.tables[table_idx].recalculate()?;
             }
-            Operator::ExtendHeaders => {
-                let mut cell_data = Vec::new();
-                while let Some(next_token) = token.clone().next() {
-                    let cell_element = Self::parse_double_quote(&mut token).map_err(|e| {
-                        eyre!(
-                            "{code_line}\n{carrot}\n{error}",
-                            carrot = String::from_iter(
-                                std::iter::repeat(' ')
-                                    .take(read_pointer)
-                                    .chain(std::iter::repeat('^').take(next_token.len()))
-                            ),
-                            error = e,
-                        )
-                    })?;
-                    read_pointer += cell_element.len() + 3;
-                    cell_data.push(cell_element);
+            Statement::Set {
+                table,
+                cell: CellRef { row, col },
+                value,
+            } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                table.set_cell(row, col, Cell::from_str(&value)?)?;
+                table.recalculate()?;
+            }
+            Statement::DeleteRow { table, row } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                table.delete_row(row)?;
+                table.recalculate()?;
+            }
+            Statement::InsertRow { table, row, cells } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                table.insert_row(row, cells.iter().map(AsRef::as_ref).collect())?;
+                table.recalculate()?;
+            }
+            Statement::UpdateRow { table, row, cells } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                table.update_row(row, cells.iter().map(AsRef::as_ref).collect())?;
+                table.recalculate()?;
+            }
+            Statement::AddColumn {
+                table,
+                header,
+                value,
+            } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                let value = value.as_deref().map_or(Ok(Cell::Empty), Cell::from_str)?;
+                table.add_column(Cell::from_str(&header)?, value)?;
+                table.recalculate()?;
+            }
+            Statement::DropColumn { table, column } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                table.drop_column(table.column_position(&column)?)?;
+                table.recalculate()?;
+            }
+            Statement::RenameColumn {
+                table,
+                column,
+                header,
+            } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                table.rename_column(table.column_position(&column)?, Cell::from_str(&header)?)?;
+            }
+            Statement::MoveColumn { table, column, to } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                table.move_column(table.column_position(&column)?, to - 1)?;
+                table.recalculate()?;
+            }
+            Statement::ReorderColumns { table, columns } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                let columns = columns
+                    .iter()
+                    .map(|column| table.column_position(column))
+                    .collect::<Result<Vec<_>>>()?;
+                table.reorder_columns(&columns)?;
+                table.recalculate()?;
+            }
+            Statement::Sort { table, keys } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let table = &mut self.tables[table_idx];
+                let keys = keys
+                    .iter()
+                    .map(|key| Ok((table.column_position(&key.column)?, key.descending)))
+                    .collect::<Result<Vec<_>>>()?;
+                table.sort_rows(&keys);
+                table.recalculate()?;
+            }
+            Statement::Filter {
+                table,
+                conditions,
+                into,
+            } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let source = &self.tables[table_idx];
+                let conditions = conditions
+                    .into_iter()
+                    .map(|condition| {
+                        Ok((
+                            source.column_position(&condition.column)?,
+                            condition.operation,
+                            condition.value,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                match into {
+                    Some(into) if into.name != table.name => {
+                        let filtered = source.filtered_copy(into.name.clone(), &conditions);
+                        let into_idx = self.create_table(&into.name);
+                        self.tables[into_idx] = filtered;
+                    }
+                    _ => {
+                        let table = &mut self.tables[table_idx];
+                        table.retain_rows(&conditions);
+                        table.recalculate()?;
+                    }
                 }
-
-                let cell_data = cell_data
+            }
+            Statement::GroupBy {
+                table,
+                columns,
+                aggregates,
+                into,
+            } => {
+                let source = &self.tables[self.table_index(code_line, &table)?];
+                let columns = columns
+                    .iter()
+                    .map(|column| source.column_position(column))
+                    .collect::<Result<Vec<_>>>()?;
+                let aggregates = aggregates
+                    .iter()
+                    .map(|(function, column)| Ok((*function, source.column_position(column)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                let summary = source.group_by(into.name.clone(), &columns, &aggregates);
+                let into_idx = self.create_table(&into.name);
+                self.tables[into_idx] = summary;
+            }
+            Statement::Pivot {
+                table,
+                rows,
+                columns,
+                aggregate: (function, values),
+                into,
+            } => {
+                let source = &self.tables[self.table_index(code_line, &table)?];
+                let pivot = source.pivot(
+                    into.name,
+                    source.column_position(&rows)?,
+                    source.column_position(&columns)?,
+                    (function, source.column_position(&values)?),
+                );
+                let into_idx = self.create_table(pivot.identifier());
+                self.tables[into_idx] = pivot;
+            }
+            Statement::Join {
+                table,
+                other,
+                kind,
+                keys: (left_key, right_key),
+                into,
+            } => {
+                let left = &self.tables[self.table_index(code_line, &table)?];
+                let right = &self.tables[self.table_index(code_line, &other)?];
+                let joined = left.join(
+                    right,
+                    into.name,
+                    (
+                        left.column_position(&left_key)?,
+                        right.column_position(&right_key)?,
+                    ),
+                    kind,
+                );
+                let into_idx = self.create_table(joined.identifier());
+                self.tables[into_idx] = joined;
+            }
+            Statement::Append { table, other } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let other_idx = self.table_index(code_line, &other)?;
+                let rows = self.tables[table_idx].rows_from(&self.tables[other_idx])?;
+                let table = &mut self.tables[table_idx];
+                table.rows.extend(rows);
+                table.recalculate()?;
+            }
+            Statement::ExtendHeaders { table, headers } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let cell_data = headers
                     .into_iter()
                     .map(|x| Cell::from_str(&x).unwrap_or_default())
                     .collect();
                 self.tables[table_idx].extend_headers(cell_data);
             }
-            Operator::View => {
-                let table = &self.tables[table_idx];
+            Statement::View { table } => {
+                let table = &self.tables[self.table_index(code_line, &table)?];
                 println!("{}", table.save_to_string(OutputType::Csv)?);
-                table.table_view()?;
+                let llm = self
+                    .backend()
+                    .map_err(|e| warn!("{e} Choosing a plot locally."))
+                    .ok();
+                table.table_view(llm.as_deref(), &self.config.plot_output())?;
             }
-            Operator::Apply => {
+            Statement::Apply {
+                table,
+                closure,
+                range,
+            } => {
                 // this will be highly experimental code
                 warn!("This feature is in alpha stage, it may not work as expected.");
-                let closure_name_token = token.next().ok_or_else(|| {
-                    eyre!(
-                        "{code_line}\n{carrot}\nNo token found for closure name.",
-                        carrot = String::from_iter(
-                            std::iter::repeat(' ')
-                                .take(read_pointer)
-                                .chain(std::iter::repeat('^').take(1))
-                        ),
-                    )
-                })?;
-                read_pointer += closure_name_token.len() + 1;
-                if let Some(&closure) = self.closure_idx.get(closure_name_token) {
-                    let closure = &self.closures[closure];
-                    let table = &mut self.tables[table_idx];
-                    let mut get_coord = || -> Result<(usize, usize)> {
-                        let start_i = token.next().ok_or_else(|| {
-                            eyre!(
-                                "{code_line}\n{carrot}\nNo token found for row-index.",
-                                carrot = String::from_iter(
-                                    std::iter::repeat(' ')
-                                        .take(read_pointer)
-                                        .chain(std::iter::repeat('^').take(1))
-                                ),
-                            )
-                        })?;
-                        read_pointer += start_i.len() + 1;
-                        let start_j = token.next().ok_or_else(|| {
-                            eyre!(
-                                "{code_line}\n{carrot}\nNo token found for column-index.",
-                                carrot = String::from_iter(
-                                    std::iter::repeat(' ')
-                                        .take(read_pointer)
-                                        .chain(std::iter::repeat('^').take(1))
-                                ),
-                            )
-                        })?;
-                        let start = (start_i.parse::<usize>()?, start_j.parse::<usize>()?);
-                        Ok(start)
-                    };
-                    let start = get_coord()?;
-                    let end = get_coord().unwrap_or_else(|e| {
-                        warn!("Defaulting end to start cell address. {e}");
-                        start
-                    });
-                    closure.apply(table, start, end, &self.closures)?;
-                } else {
-                    bail!(
-                        "{code_line}\n{carrot}\nNo closure found with name {closure_name_token}.",
-                        carrot = String::from_iter(
-                            std::iter::repeat(' ')
-                                .take(read_pointer)
-                                .chain(std::iter::repeat('^').take(closure_name_token.len()))
-                        ),
-                    );
-                }
+                let table_idx = self.table_index(code_line, &table)?;
+                let Some(&closure_idx) = self.closure_idx.get(&closure.name) else {
+                    bail!(closure.span.report(
+                        code_line,
+                        format!("No closure found with name {}.", closure.name)
+                    ));
+                };
+                let (start, end) = self.tables[table_idx].resolve_range(&range)?;
+                let scope = ClosureScope {
+                    closures: &self.closures,
+                    closure_idx: &self.closure_idx,
+                };
+                self.closures[closure_idx].apply(
+                    &mut self.tables[table_idx],
+                    start,
+                    end,
+                    &scope,
+                )?;
+                self.tables[table_idx].recalculate()?;
             }
-            Operator::DefineClosure => {
-                // as it contains invalid data, so to prevent accidental use
-                drop(table_idx);
+            Statement::DefineClosure { name, definition } => {
                 warn!("This feature is in alpha stage, it may not work as expected.");
-                let defination = token.collect::<Vec<&str>>().join(" ");
-                let defination = TableClosure::new(defination)?;
+                let definition = TableClosure::new(name.name.clone(), definition)?;
                 let closure_idx = *self
                     .closure_idx
-                    .entry(table_name_token.to_string())
-                    .and_modify(|_| {
-                        warn!("Closure {table_name_token} already existed, overwriting it.",);
+                    .entry(name.name.clone())
+                    .and_modify(|&mut idx| {
+                        // built-ins may be redefined silently, as in the older examples
+                        if !self.closures[idx].is_native() {
+                            warn!("Closure {} already existed, overwriting it.", name.name);
+                        }
                     })
                     .or_insert_with(|| self.closures.len());
                 if let Some(v) = self.closures.get_mut(closure_idx) {
-                    *v = defination;
+                    *v = definition;
                 } else {
-                    self.closures.push(defination);
+                    self.closures.push(definition);
                 }
             }
-            Operator::Prompt => {
-                use chatgpt::prelude::*;
-
-                let prompt = token.collect::<Vec<&str>>().join(" ");
-                let prompt = prompt.trim_matches('"');
-                let table = &self.tables[table_idx];
-                if self.tables.len() > 1 {
-                    warn!("You can only reference one table in prompt for now.");
+            Statement::Plot {
+                table,
+                kind,
+                axes,
+                output,
+            } => {
+                let table = &self.tables[self.table_index(code_line, &table)?];
+                let graphs = explicit_plot(code_line, table, &kind, &axes)?;
+                let path = output.unwrap_or_else(|| self.config.plot_output());
+                table.render_plot(&graphs, &path)?;
+                println!("Plot written to {path}.");
+            }
+            Statement::Prompt { tables, prompt } => {
+                let mut table_indices = Vec::new();
+                for table in &tables {
+                    let table_idx = self.table_index(code_line, table)?;
+                    if !table_indices.contains(&table_idx) {
+                        table_indices.push(table_idx);
+                    }
                 }
-                let mut prompt_text = format!(
-                    "I am working with CSV files. My table is as follows:\nTable name:{table}\nI want you to answer my next following question. You are expected to keep the answer as short as possible.\n",
-                    table = table.to_csv()?
+                let llm = self.backend()?;
+                let prompt_text = self.table_prompt(
+                    &table_indices,
+                    "I want you to answer my next following question. You are expected to keep the answer as short as possible.",
+                    &prompt,
                 );
-                prompt_text.push_str(prompt);
-                // println!("Prompt: {}", prompt_text);
-                let client = ChatGPT::new(env!(
-                    "OPENAI_KEY",
-                    "OpenAI key not provided! Required to use prompt."
-                ))?;
-                use tokio::runtime::Runtime;
-                let rt = Runtime::new().unwrap();
-
-                let prompt_success: Result<()> = rt.block_on(async {
-                    let response = client.send_message(prompt_text).await?;
-                    println!("Response: {}", response.message().content);
-                    Ok(())
-                });
-                prompt_success?;
+                let response = llm
+                    .complete(&prompt_text)
+                    .map_err(|e| eyre::eyre!("{} backend failed: {e}", llm.name()))?;
+                println!("Response: {}", response);
+            }
+            Statement::PromptInto {
+                table,
+                cell: CellRef { row, col },
+                prompt,
+            } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                // check the cell exists before asking
+                self.tables[table_idx].get_cell(row, col)?;
+                let llm = self.backend()?;
+                let prompt_text = self.table_prompt(&[table_idx], CELL_ANSWER, &prompt);
+                let response = llm
+                    .complete(&prompt_text)
+                    .map_err(|e| eyre::eyre!("{} backend failed: {e}", llm.name()))?;
+                let answer = answer_cell(&response);
+                println!("Response: {}", answer);
+                *self.tables[table_idx].get_cell_mut(row, col)? = answer;
+                self.tables[table_idx].recalculate()?;
+            }
+            Statement::FillColumn {
+                table,
+                column,
+                prompt,
+            } => {
+                let table_idx = self.table_index(code_line, &table)?;
+                let llm = self.backend()?;
+                let table = &mut self.tables[table_idx];
+                let col = match table.header_index(&column) {
+                    Some(col) => col,
+                    None => table.push_column(Cell::String(column)),
+                };
+                for row in 0..table.dimensions().0 {
+                    let prompt_text = format!(
+                        "I am working with CSV files. This is one row of my table:\nTable name:{context}\n{CELL_ANSWER}\n{prompt}",
+                        context = table.row_prompt_csv(row)?
+                    );
+                    let response = llm
+                        .complete(&prompt_text)
+                        .map_err(|e| eyre::eyre!("{} backend failed: {e}", llm.name()))?;
+                    *table.get_cell_mut(row, col)? = answer_cell(&response);
+                }
+                println!("Filled {} rows.", table.dimensions().0);
+                table.recalculate()?;
             }
         }
         Ok(())
     }
+
+    /// Wraps `question` with the listed tables, split evenly over the prompt token budget.
+    fn table_prompt(&self, table_indices: &[usize], instruction: &str, question: &str) -> String {
+        let budget = (self.config.prompt_token_budget() * CHARS_PER_TOKEN)
+            .saturating_sub(question.len() + PROMPT_PREAMBLE_LEN)
+            / table_indices.len().max(1);
+        let mut context = String::new();
+        for table_idx in table_indices {
+            context.push_str("Table name:");
+            context.push_str(&self.tables[*table_idx].to_prompt_csv(budget));
+        }
+        format!(
+            "I am working with CSV files. My {} as follows:\n{context}\n{instruction}\n{question}",
+            if table_indices.len() > 1 {
+                "tables are"
+            } else {
+                "table is"
+            }
+        )
+    }
+}
+
+/// Instruction for prompts whose answer is stored in a cell.
+const CELL_ANSWER: &str = "Answer my next following question with a single value that fits in one spreadsheet cell, without any explanation.";
+
+/// First line of a model reply as a cell, without surrounding quotes.
+fn answer_cell(response: &str) -> Cell {
+    let answer = response
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .trim_matches(['"', '`']);
+    Cell::from_str(answer).unwrap_or_default()
+}
+
+/// Resolves the kind and axes of a `plot` statement against the columns of `table`, one
+/// graph per y column.
+fn explicit_plot(
+    code_line: &str,
+    table: &Table,
+    kind: &Ident,
+    axes: &[PlotAxis],
+) -> Result<Vec<ResponsePlotType>> {
+    let mut x = None;
+    let mut ys = Vec::new();
+    for axis in axes {
+        let index = match &axis.column {
+            ColumnRef::Index(index) if *index <= table.dimensions().1 => index - 1,
+            ColumnRef::Index(index) => {
+                return Err(axis.span.report(
+                    code_line,
+                    format!(
+                        "Column {index} is out of bounds, table has {} columns.",
+                        table.dimensions().1
+                    ),
+                ))
+            }
+            ColumnRef::Name(name) => table.header_index(name).ok_or_else(|| {
+                axis.span.report(
+                    code_line,
+                    format!("No column named \"{name}\" in table {}.", table.title()),
+                )
+            })?,
+        };
+        match axis.axis.as_str() {
+            "x" | "col" | "column" => {
+                if x.replace(index).is_some() {
+                    warn!("Axis x is given more than once, using the last one.");
+                }
+            }
+            "y" => ys.push(index),
+            other => {
+                return Err(axis.span.report(
+                    code_line,
+                    format!("Unknown plot axis \"{other}\", expected x, y or col."),
+                ))
+            }
+        }
+    }
+    let missing = |axis: &str| {
+        kind.span.report(
+            code_line,
+            format!("Plot {} needs a column for axis {axis}.", kind.name),
+        )
+    };
+    let x = x.ok_or_else(|| missing("x"))?;
+    let series = |graph: fn((usize, usize)) -> ResponsePlotType| {
+        if ys.is_empty() {
+            return Err(missing("y"));
+        }
+        Ok(ys.iter().map(|&y| graph((x, y))).collect())
+    };
+    match kind.name.as_str() {
+        "bar" | "bargraph" => series(ResponsePlotType::Bargraph),
+        "scatter" | "scatterplot" => series(ResponsePlotType::Scatterplot),
+        "line" | "linegraph" => series(ResponsePlotType::Linegraph),
+        "hist" | "histogram" => {
+            if !ys.is_empty() {
+                warn!("Histograms only use the x axis, ignoring y.");
+            }
+            Ok(vec![ResponsePlotType::Histogram(x)])
+        }
+        "pie" | "piechart" => {
+            if ys.len() > 1 {
+                return Err(kind
+                    .span
+                    .report(code_line, "Pie charts take a single y column."));
+            }
+            Ok(vec![ResponsePlotType::Piechart((x, ys.first().copied()))])
+        }
+        unknown => Err(kind.span.report(
+            code_line,
+            format!("\"{unknown}\" is not a plot kind, expected bar, line, scatter, hist or pie."),
+        )),
+    }
 }
 
 impl Save for VM {
-    fn to_csv(&self) -> Result<String> {
+    fn to_csv_with_title(&self, title: bool) -> Result<String> {
         self.tables
             .iter()
-            .map(|table| table.to_csv())
+            .map(|table| table.to_csv_with_title(title))
             .collect::<Result<Vec<_>>>()
-            .map(|tables| tables.join("\n"))
+            .map(|tables| tables.join("\r\n"))
     }
 
     fn to_html(&self) -> Result<String> {
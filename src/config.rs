@@ -0,0 +1,109 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{suggestion, warn};
+
+/// Name of the config file looked up in the working directory.
+pub const CONFIG_FILE: &str = "spread-lang.conf";
+
+const DEFAULT_PROMPT_TOKEN_BUDGET: usize = 3000;
+
+/// Settings read at runtime from environment variables and `key = value` config files.
+///
+/// Files are read from `./spread-lang.conf` and then `$XDG_CONFIG_HOME/spread-lang/config`
+/// (or `~/.config/spread-lang/config`), the first file defining a key wins. Environment
+/// variables win over both.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let mut config = Config::default();
+        for path in Self::search_paths() {
+            if let Ok(text) = std::fs::read_to_string(&path) {
+                config.merge(&text, &path);
+            }
+        }
+        config
+    }
+
+    fn search_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        if let Some(config_home) = config_home {
+            paths.push(config_home.join("spread-lang").join("config"));
+        }
+        paths
+    }
+
+    /// Adds `key = value` lines from `text`, keeping keys that are already set.
+    pub(crate) fn merge(&mut self, text: &str, path: &std::path::Path) {
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                warn!(
+                    "Ignoring line {} of {}, expected \"key = value\".",
+                    line_no + 1,
+                    path.display()
+                );
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            self.values.entry(key.trim().to_string()).or_insert(value);
+        }
+    }
+
+    /// Value of `env_var` if set, otherwise `key` from the config files.
+    pub fn get(&self, key: &str, env_var: &str) -> Option<String> {
+        std::env::var(env_var)
+            .ok()
+            .or_else(|| self.values.get(key).cloned())
+            .filter(|value| !value.trim().is_empty())
+    }
+
+    pub fn openai_key(&self) -> Option<String> {
+        self.get("openai_key", "OPENAI_KEY")
+    }
+
+    /// File plots are written to unless a `plot` statement names one.
+    pub fn plot_output(&self) -> String {
+        self.get("plot_output", "SPREAD_PLOT_OUTPUT")
+            .unwrap_or_else(|| "plot.html".to_string())
+    }
+
+    /// Whether LLM responses are cached, on unless `llm_cache` is `false`, `off` or `0`.
+    pub fn llm_cache(&self) -> bool {
+        !matches!(
+            self.get("llm_cache", "SPREAD_LLM_CACHE").as_deref(),
+            Some("false" | "off" | "0")
+        )
+    }
+
+    /// Rough number of tokens of table data a prompt may carry, tables beyond it are sampled.
+    pub fn prompt_token_budget(&self) -> usize {
+        let budget = self.get("prompt_token_budget", "SPREAD_PROMPT_TOKEN_BUDGET");
+        match budget.as_deref().map(str::parse) {
+            Some(Ok(budget)) => budget,
+            Some(Err(_)) => {
+                warn!(
+                    "prompt_token_budget must be a whole number, using {DEFAULT_PROMPT_TOKEN_BUDGET}."
+                );
+                DEFAULT_PROMPT_TOKEN_BUDGET
+            }
+            None => DEFAULT_PROMPT_TOKEN_BUDGET,
+        }
+    }
+
+    /// Prints how to provide the OpenAI key, used when an AI feature is requested without one.
+    pub fn missing_key_hint() {
+        suggestion!(
+            "Set the \x1b[96mOPENAI_KEY\x1b[0m environment variable or add \x1b[96mopenai_key = \"...\"\x1b[0m to {CONFIG_FILE}."
+        );
+    }
+}
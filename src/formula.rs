@@ -0,0 +1,692 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+};
+
+use crate::{
+    closure::Operation,
+    compiler::{Cell, Table},
+};
+use eyre::{bail, eyre, Result};
+
+/// Position of a data cell written in A1 notation, `A1` is the first column of the first row
+/// after the headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CellRef {
+    pub row: usize,
+    pub col: usize,
+}
+
+impl CellRef {
+    /// Reads `B3` style references, column letters are case insensitive and the `$` of
+    /// `$B$3` is skipped.
+    pub fn parse(text: &str) -> Option<CellRef> {
+        Reference::parse(text).map(|reference| reference.cell)
+    }
+}
+
+/// Reference as written in a formula, a `$` before the column letters or the row number
+/// keeps it in place when the formula is filled down.
+#[derive(Debug, Clone, Copy)]
+struct Reference {
+    cell: CellRef,
+    fixed_col: bool,
+    fixed_row: bool,
+}
+
+impl Reference {
+    fn parse(text: &str) -> Option<Reference> {
+        let (fixed_col, text) = text
+            .strip_prefix('$')
+            .map_or((false, text), |text| (true, text));
+        let split = text.find(|c: char| !c.is_ascii_alphabetic())?;
+        let (letters, digits) = text.split_at(split);
+        let (fixed_row, digits) = digits
+            .strip_prefix('$')
+            .map_or((false, digits), |digits| (true, digits));
+        if letters.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let row = digits.parse::<usize>().ok()?.checked_sub(1)?;
+        Some(Reference {
+            cell: CellRef {
+                row,
+                col: column_index(letters)?,
+            },
+            fixed_col,
+            fixed_row,
+        })
+    }
+    /// Same reference, anchors included, to `cell`.
+    fn at(self, cell: CellRef) -> Reference {
+        Reference { cell, ..self }
+    }
+}
+
+impl Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let anchor = |fixed| if fixed { "$" } else { "" };
+        write!(
+            f,
+            "{}{}{}{}",
+            anchor(self.fixed_col),
+            column_name(self.cell.col),
+            anchor(self.fixed_row),
+            self.cell.row + 1
+        )
+    }
+}
+
+impl From<(usize, usize)> for CellRef {
+    fn from((row, col): (usize, usize)) -> Self {
+        CellRef { row, col }
+    }
+}
+
+impl Display for CellRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", column_name(self.col), self.row + 1)
+    }
+}
+
+/// Cells a statement works on, resolved against a table with `Table::resolve_range`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellRange {
+    /// Rectangle between two corners, from `A1:C10`, `B2` or row and column indices.
+    Cells(CellRef, CellRef),
+    /// Every row of the columns from the first to the last, written `B:B` or `B:D`.
+    Columns(usize, usize),
+    /// Every row of the column with this header.
+    Header(String),
+}
+
+impl CellRange {
+    /// Reads `B2`, `A1:C10` or `B:D`.
+    pub fn parse(text: &str) -> Option<CellRange> {
+        let Some((from, to)) = text.split_once(':') else {
+            let cell = CellRef::parse(text)?;
+            return Some(CellRange::Cells(cell, cell));
+        };
+        if let (Some(from), Some(to)) = (CellRef::parse(from), CellRef::parse(to)) {
+            return Some(CellRange::Cells(from, to));
+        }
+        Some(CellRange::Columns(column_index(from)?, column_index(to)?))
+    }
+}
+
+/// 0-based index of spreadsheet column letters, `A` is 0 and `AA` is 26.
+pub fn column_index(letters: &str) -> Option<usize> {
+    if letters.is_empty() || letters.len() > 6 {
+        return None;
+    }
+    letters
+        .chars()
+        .try_fold(0_usize, |index, c| {
+            c.is_ascii_alphabetic()
+                .then(|| index * 26 + (c.to_ascii_uppercase() as u8 - b'A') as usize + 1)
+        })
+        .map(|index| index - 1)
+}
+
+/// Spreadsheet letters of the 0-based column `index`.
+pub fn column_name(mut index: usize) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push((b'A' + (index % 26) as u8) as char);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    letters.iter().rev().collect()
+}
+
+/// Function reducing a list of numbers, used in formulas and in `group_by` summaries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl FromStr for Function {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_uppercase().as_str() {
+            "SUM" => Function::Sum,
+            "AVG" | "AVERAGE" => Function::Avg,
+            "MIN" => Function::Min,
+            "MAX" => Function::Max,
+            "COUNT" => Function::Count,
+            _ => bail!("Unknown function {s}, expected SUM, AVG, MIN, MAX or COUNT."),
+        })
+    }
+}
+
+impl Function {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Function::Sum => "sum",
+            Function::Avg => "avg",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Count => "count",
+        }
+    }
+    /// Reduces `numbers`, `None` for the average, minimum or maximum of no numbers.
+    pub(crate) fn apply(&self, numbers: &[f64]) -> Option<f64> {
+        Some(match self {
+            // std `Iterator::sum` of an empty f64 iterator is -0.0
+            Function::Sum => numbers.iter().fold(0_f64, |total, n| total + n),
+            Function::Count => numbers.len() as f64,
+            Function::Avg if numbers.is_empty() => return None,
+            Function::Avg => numbers.iter().sum::<f64>() / numbers.len() as f64,
+            Function::Min => numbers.iter().copied().reduce(f64::min)?,
+            Function::Max => numbers.iter().copied().reduce(f64::max)?,
+        })
+    }
+    /// Reduces the cells of a range, used by `apply` and `group_by`. `count` counts the
+    /// non-empty cells, the others use the numbers (also numeric text) and skip the rest.
+    pub(crate) fn aggregate<'a>(&self, cells: impl IntoIterator<Item = &'a Cell>) -> Option<f64> {
+        let cells = cells.into_iter();
+        if *self == Function::Count {
+            return Some(cells.filter(|cell| **cell != Cell::Empty).count() as f64);
+        }
+        self.apply(&cells.filter_map(Cell::numeric).collect::<Vec<_>>())
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Cell),
+    Ref(CellRef),
+    /// Rectangle between two corners, only valid as a function argument.
+    Range(CellRef, CellRef),
+    Negate(Box<Expr>),
+    Binary(Operation, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+/// Expression stored in a cell, written `=SUM(B1:B3) * 2`, together with its last computed
+/// value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formula {
+    source: String,
+    expr: Expr,
+    value: Cell,
+}
+
+impl Formula {
+    /// Parses the text after the leading `=`.
+    pub fn parse(source: &str) -> Result<Formula> {
+        let mut parser = FormulaParser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let expr = parser.comparison()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            bail!("Unexpected \"{token}\" in formula ={source}.");
+        }
+        if matches!(expr, Expr::Range(..)) {
+            bail!("A range can only be used inside a function, in formula ={source}.");
+        }
+        Ok(Formula {
+            source: source.to_string(),
+            expr,
+            value: Cell::Empty,
+        })
+    }
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+    /// Value from the last recalculation.
+    pub fn value(&self) -> &Cell {
+        &self.value
+    }
+    pub(crate) fn set_value(&mut self, value: Cell) {
+        self.value = value;
+    }
+    /// Every cell the formula reads, ranges expanded.
+    pub(crate) fn references(&self) -> Vec<CellRef> {
+        fn collect(expr: &Expr, refs: &mut Vec<CellRef>) {
+            match expr {
+                Expr::Literal(_) => {}
+                Expr::Ref(cell) => refs.push(*cell),
+                Expr::Range(from, to) => refs.extend(range_cells(*from, *to)),
+                Expr::Negate(expr) => collect(expr, refs),
+                Expr::Binary(_, lhs, rhs) => {
+                    collect(lhs, refs);
+                    collect(rhs, refs);
+                }
+                Expr::Call(_, args) => args.iter().for_each(|arg| collect(arg, refs)),
+            }
+        }
+        let mut refs = Vec::new();
+        collect(&self.expr, &mut refs);
+        refs
+    }
+    /// Copy of the formula with every reference passed through `map`, `None` when a
+    /// reference has nowhere to go. A range keeps the cells `map` still places, as long as
+    /// they form a rectangle, so it shrinks when rows at its edge are deleted.
+    pub(crate) fn map_references(
+        &self,
+        mut map: impl FnMut(CellRef) -> Option<CellRef>,
+    ) -> Option<Formula> {
+        self.rewrite(|from, to| match to {
+            None => Some(from.at(map(from.cell)?).to_string()),
+            Some(to) => {
+                let (first, last) = map_range(from.cell, to.cell, &mut map)?;
+                Some(format!("{}:{}", from.at(first), to.at(last)))
+            }
+        })
+    }
+    /// Copy of the formula for the cell `rows` further down, as when dragging it in a
+    /// spreadsheet: references move along, except rows fixed with `$`.
+    pub(crate) fn fill_down(&self, rows: usize) -> Option<Formula> {
+        let shift = |reference: Reference| {
+            if reference.fixed_row {
+                return reference;
+            }
+            reference.at(CellRef {
+                row: reference.cell.row + rows,
+                col: reference.cell.col,
+            })
+        };
+        self.rewrite(|from, to| {
+            Some(match to {
+                None => shift(from).to_string(),
+                Some(to) => format!("{}:{}", shift(from), shift(to)),
+            })
+        })
+    }
+    /// Copy of the formula with each reference and each range, given with its end, written
+    /// as `rewrite` returns it, `None` when `rewrite` returns `None` for any of them.
+    fn rewrite(
+        &self,
+        mut rewrite: impl FnMut(Reference, Option<Reference>) -> Option<String>,
+    ) -> Option<Formula> {
+        let mut source = String::with_capacity(self.source.len());
+        let mut rest = self.source.as_str();
+        while !rest.is_empty() {
+            let len = token_len(rest);
+            match Reference::parse(&rest[..len]) {
+                Some(from) => {
+                    let to = rest[len..]
+                        .trim_start()
+                        .strip_prefix(':')
+                        .map(str::trim_start)
+                        .and_then(|after| {
+                            let len = token_len(after);
+                            Some((Reference::parse(&after[..len])?, &after[len..]))
+                        });
+                    if let Some((to, after)) = to {
+                        source.push_str(&rewrite(from, Some(to))?);
+                        rest = after;
+                        continue;
+                    }
+                    source.push_str(&rewrite(from, None)?);
+                }
+                None => source.push_str(&rest[..len]),
+            }
+            rest = &rest[len..];
+        }
+        Formula::parse(&source).ok()
+    }
+    /// Computes the formula from the current values of `table`, errors become `#REF!`,
+    /// `#DIV/0!` or `#VALUE!` cells as in a spreadsheet.
+    pub(crate) fn evaluate(&self, table: &Table) -> Cell {
+        evaluate(&self.expr, table).unwrap_or_else(|e| Cell::String(e.to_string()))
+    }
+}
+
+/// Reason a formula could not be computed, shown in its cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FormulaError {
+    /// A reference outside the table.
+    Ref,
+    DivideByZero,
+    /// An operand of the wrong type.
+    Value,
+}
+
+impl Display for FormulaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormulaError::Ref => write!(f, "#REF!"),
+            FormulaError::DivideByZero => write!(f, "#DIV/0!"),
+            FormulaError::Value => write!(f, "#VALUE!"),
+        }
+    }
+}
+
+/// Length of the formula token `text` starts with, string literals included.
+fn token_len(text: &str) -> usize {
+    match text.chars().next() {
+        Some('"') => text[1..].find('"').map_or(text.len(), |end| end + 2),
+        Some(c) if c.is_ascii_digit() || c == '.' => text
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(text.len()),
+        Some(c) if c.is_ascii_alphabetic() || c == '$' => text
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '$'))
+            .unwrap_or(text.len()),
+        Some(c) => c.len_utf8(),
+        None => 0,
+    }
+}
+
+/// Corners of the rectangle the cells of `from:to` form once passed through `map`, `None`
+/// when `map` keeps none of them or they are no longer one block.
+fn map_range(
+    from: CellRef,
+    to: CellRef,
+    map: &mut impl FnMut(CellRef) -> Option<CellRef>,
+) -> Option<(CellRef, CellRef)> {
+    let cells: HashSet<CellRef> = range_cells(from, to).filter_map(map).collect();
+    let first = CellRef {
+        row: cells.iter().map(|cell| cell.row).min()?,
+        col: cells.iter().map(|cell| cell.col).min()?,
+    };
+    let last = CellRef {
+        row: cells.iter().map(|cell| cell.row).max()?,
+        col: cells.iter().map(|cell| cell.col).max()?,
+    };
+    let area = (last.row - first.row + 1) * (last.col - first.col + 1);
+    (cells.len() == area).then_some((first, last))
+}
+
+fn range_cells(from: CellRef, to: CellRef) -> impl Iterator<Item = CellRef> {
+    let rows = from.row.min(to.row)..=from.row.max(to.row);
+    let cols = from.col.min(to.col)..=from.col.max(to.col);
+    rows.flat_map(move |row| cols.clone().map(move |col| CellRef { row, col }))
+}
+
+fn cell_value(table: &Table, cell: CellRef) -> Result<Cell, FormulaError> {
+    table
+        .get_cell(cell.row, cell.col)
+        .map(|cell| cell.value().clone())
+        .map_err(|_| FormulaError::Ref)
+}
+
+fn evaluate(expr: &Expr, table: &Table) -> Result<Cell, FormulaError> {
+    Ok(match expr {
+        Expr::Literal(cell) => cell.clone(),
+        Expr::Ref(cell) => cell_value(table, *cell)?,
+        Expr::Range(..) => return Err(FormulaError::Value),
+        Expr::Negate(expr) => Cell::Empty - evaluate(expr, table)?,
+        Expr::Binary(op, lhs, rhs) => {
+            let (lhs, rhs) = (evaluate(lhs, table)?, evaluate(rhs, table)?);
+            let zero = matches!(rhs, Cell::Number(n) if n == 0_f64) || rhs == Cell::Boolean(false);
+            if zero && matches!(op, Operation::Divide | Operation::Mod) {
+                return Err(FormulaError::DivideByZero);
+            }
+            op.apply(lhs, rhs).map_err(|_| FormulaError::Value)?
+        }
+        Expr::Call(function, args) => {
+            // like a spreadsheet, text and empty cells in ranges are skipped
+            let mut numbers = Vec::new();
+            for arg in args {
+                match arg {
+                    Expr::Range(from, to) => {
+                        for cell in range_cells(*from, *to) {
+                            if let Cell::Number(n) = cell_value(table, cell)? {
+                                numbers.push(n);
+                            }
+                        }
+                    }
+                    arg => match evaluate(arg, table)? {
+                        Cell::Number(n) => numbers.push(n),
+                        Cell::Boolean(b) => numbers.push(b as u8 as f64),
+                        Cell::Empty => {}
+                        _ => return Err(FormulaError::Value),
+                    },
+                }
+            }
+            let error = match function {
+                Function::Avg => FormulaError::DivideByZero,
+                _ => FormulaError::Value,
+            };
+            Cell::Number(function.apply(&numbers).ok_or(error)?)
+        }
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FormulaToken {
+    Number(f64),
+    Str(String),
+    Word(String),
+    Symbol(&'static str),
+}
+
+impl Display for FormulaToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormulaToken::Number(n) => write!(f, "{n}"),
+            FormulaToken::Str(s) => write!(f, "\"{s}\""),
+            FormulaToken::Word(w) => write!(f, "{w}"),
+            FormulaToken::Symbol(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 14] = [
+    "<=", ">=", "<>", "==", "+", "-", "*", "/", "(", ")", ",", ":", "<", ">",
+];
+
+fn tokenize(source: &str) -> Result<Vec<FormulaToken>> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '"' {
+            let end = rest[1..]
+                .find('"')
+                .ok_or_else(|| eyre!("String literal was not closed in formula ={source}."))?;
+            tokens.push(FormulaToken::Str(rest[1..end + 1].to_string()));
+            end + 2
+        } else if c.is_ascii_digit() || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let number = rest[..len]
+                .parse()
+                .map_err(|_| eyre!("Invalid number {} in formula ={source}.", &rest[..len]))?;
+            tokens.push(FormulaToken::Number(number));
+            len
+        } else if c.is_ascii_alphabetic() || c == '$' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '$'))
+                .unwrap_or(rest.len());
+            tokens.push(FormulaToken::Word(rest[..len].to_string()));
+            len
+        } else if c == '=' && !rest.starts_with("==") {
+            tokens.push(FormulaToken::Symbol("=="));
+            1
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push(FormulaToken::Symbol(symbol));
+            symbol.len()
+        } else {
+            bail!("Unexpected character '{c}' in formula ={source}.");
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over comparison, then `+ -`, then `* /`, then unary minus.
+struct FormulaParser {
+    tokens: Vec<FormulaToken>,
+    pos: usize,
+}
+
+impl FormulaParser {
+    fn next(&mut self) -> Option<FormulaToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    /// Consumes the next token if it is one of `symbols`.
+    fn symbol(&mut self, symbols: &[&str]) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(FormulaToken::Symbol(symbol)) if symbols.contains(symbol) => {
+                self.pos += 1;
+                Some(symbol)
+            }
+            _ => None,
+        }
+    }
+    fn expect(&mut self, symbol: &str) -> Result<()> {
+        match self.next() {
+            Some(FormulaToken::Symbol(found)) if found == symbol => Ok(()),
+            Some(found) => bail!("Expected \"{symbol}\" but found \"{found}\" in formula."),
+            None => bail!("Expected \"{symbol}\" at the end of the formula."),
+        }
+    }
+    fn binary(&mut self, symbols: &[&str], operand: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        let mut lhs = operand(self)?;
+        while let Some(symbol) = self.symbol(symbols) {
+            let rhs = operand(self)?;
+            lhs = Expr::Binary(symbol.parse()?, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn comparison(&mut self) -> Result<Expr> {
+        self.binary(&["<=", ">=", "<>", "==", "<", ">"], Self::additive)
+    }
+    fn additive(&mut self) -> Result<Expr> {
+        self.binary(&["+", "-"], Self::multiplicative)
+    }
+    fn multiplicative(&mut self) -> Result<Expr> {
+        self.binary(&["*", "/"], Self::unary)
+    }
+    fn unary(&mut self) -> Result<Expr> {
+        if self.symbol(&["-"]).is_some() {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+    fn primary(&mut self) -> Result<Expr> {
+        Ok(match self.next() {
+            Some(FormulaToken::Number(n)) => Expr::Literal(Cell::Number(n)),
+            Some(FormulaToken::Str(s)) => Expr::Literal(Cell::String(s)),
+            Some(FormulaToken::Symbol("(")) => {
+                let expr = self.comparison()?;
+                self.expect(")")?;
+                expr
+            }
+            Some(FormulaToken::Word(word)) => {
+                if self.symbol(&["("]).is_some() {
+                    let function = word.parse()?;
+                    let mut args = Vec::new();
+                    if self.symbol(&[")"]).is_none() {
+                        loop {
+                            args.push(self.comparison()?);
+                            if self.symbol(&[")"]).is_some() {
+                                break;
+                            }
+                            self.expect(",")?;
+                        }
+                    }
+                    return Ok(Expr::Call(function, args));
+                }
+                match word.to_uppercase().as_str() {
+                    "TRUE" => return Ok(Expr::Literal(Cell::Boolean(true))),
+                    "FALSE" => return Ok(Expr::Literal(Cell::Boolean(false))),
+                    _ => {}
+                }
+                let from = CellRef::parse(&word)
+                    .ok_or_else(|| eyre!("\"{word}\" is not a cell reference like B2."))?;
+                if self.symbol(&[":"]).is_none() {
+                    return Ok(Expr::Ref(from));
+                }
+                match self.next() {
+                    Some(FormulaToken::Word(word)) => {
+                        let to = CellRef::parse(&word)
+                            .ok_or_else(|| eyre!("\"{word}\" is not a cell reference like B2."))?;
+                        Expr::Range(from, to)
+                    }
+                    _ => bail!("Expected the end of the range after \"{from}:\"."),
+                }
+            }
+            Some(token) => bail!("Unexpected \"{token}\" in formula."),
+            None => bail!("Formula ended unexpectedly."),
+        })
+    }
+}
+
+/// Orders the formula cells of a table so every formula comes after the formulas it reads.
+///
+/// Returns the order and, separately, the cells taking part in a circular reference along
+/// with a readable description of the first cycle found.
+pub(crate) fn evaluation_order(
+    formulas: &HashMap<CellRef, Vec<CellRef>>,
+) -> (Vec<CellRef>, Vec<CellRef>, Option<String>) {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Visit {
+        InProgress,
+        Done,
+    }
+    fn visit(
+        cell: CellRef,
+        formulas: &HashMap<CellRef, Vec<CellRef>>,
+        state: &mut HashMap<CellRef, Visit>,
+        path: &mut Vec<CellRef>,
+        order: &mut Vec<CellRef>,
+        cycles: &mut (Vec<CellRef>, Option<String>),
+    ) {
+        match state.get(&cell) {
+            Some(Visit::Done) => return,
+            Some(Visit::InProgress) => {
+                let start = path.iter().position(|c| *c == cell).unwrap_or(0);
+                let cycle = &path[start..];
+                cycles.0.extend(cycle.iter().copied());
+                cycles.1.get_or_insert_with(|| {
+                    cycle
+                        .iter()
+                        .chain(std::iter::once(&cell))
+                        .map(|c| c.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" -> ")
+                });
+                return;
+            }
+            None => {}
+        }
+        state.insert(cell, Visit::InProgress);
+        path.push(cell);
+        for dependency in &formulas[&cell] {
+            if formulas.contains_key(dependency) {
+                visit(*dependency, formulas, state, path, order, cycles);
+            }
+        }
+        path.pop();
+        state.insert(cell, Visit::Done);
+        order.push(cell);
+    }
+
+    let mut cells: Vec<_> = formulas.keys().copied().collect();
+    cells.sort_by_key(|cell| (cell.row, cell.col));
+    let mut state = HashMap::new();
+    let mut order = Vec::new();
+    let mut cycles = (Vec::new(), None);
+    for cell in cells {
+        visit(
+            cell,
+            formulas,
+            &mut state,
+            &mut Vec::new(),
+            &mut order,
+            &mut cycles,
+        );
+    }
+    (order, cycles.0, cycles.1)
+}
@@ -0,0 +1,157 @@
+use crate::{suggestion, warn};
+use std::fmt::Display;
+
+/// Byte range of a lexeme within the line it was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+    /// Zero width span, rendered as a single caret.
+    pub fn point(at: usize) -> Span {
+        Span { start: at, end: at }
+    }
+    /// Renders `line` followed by carets underlining this span.
+    pub fn underline(&self, line: &str) -> String {
+        let start = self.start.min(line.len());
+        let end = self.end.clamp(start, line.len());
+        let offset = line[..start].chars().count();
+        let width = line[start..end].chars().count().max(1);
+        format!("{line}\n{}{}", " ".repeat(offset), "^".repeat(width))
+    }
+    pub fn report(&self, line: &str, message: impl Display) -> eyre::Report {
+        eyre::eyre!("{}\n{message}", self.underline(line))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexemeKind {
+    /// Bare word such as a table name, operator or modifier.
+    Ident(String),
+    /// Double quoted string literal with escapes resolved.
+    Str(String),
+    Number(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub kind: LexemeKind,
+    pub span: Span,
+}
+
+impl Lexeme {
+    pub fn as_usize(&self) -> Option<usize> {
+        match self.kind {
+            LexemeKind::Number(n) if n >= 0_f64 && n.fract() == 0_f64 => Some(n as usize),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str) -> Lexer<'a> {
+        Lexer { src, pos: 0 }
+    }
+    /// Offset of the next unread byte, after skipping whitespace.
+    pub fn offset(&self) -> usize {
+        let rest = &self.src[self.pos..];
+        self.pos + (rest.len() - rest.trim_start().len())
+    }
+    pub fn peek(&self) -> Option<Lexeme> {
+        self.clone().next()
+    }
+    /// Consumes the unread remainder of the line verbatim.
+    pub fn rest(&mut self) -> Option<(&'a str, Span)> {
+        let start = self.offset();
+        let text = self.src[start..].trim_end();
+        self.pos = self.src.len();
+        if text.is_empty() {
+            None
+        } else {
+            Some((text, Span::new(start, start + text.len())))
+        }
+    }
+
+    fn read_string(&mut self, start: usize) -> Lexeme {
+        let mut string = String::new();
+        let mut chars = self.src[start + 1..].char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos = start + 1 + idx + 1;
+                    return Lexeme {
+                        kind: LexemeKind::Str(string),
+                        span: Span::new(start, self.pos),
+                    };
+                }
+                '\\' => match chars.next() {
+                    Some((_, '"')) => string.push('"'),
+                    Some((_, '\\')) => string.push('\\'),
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, 't')) => string.push('\t'),
+                    Some((_, other)) => {
+                        string.push('\\');
+                        string.push(other);
+                    }
+                    None => string.push('\\'),
+                },
+                c => string.push(c),
+            }
+        }
+        warn!("String literal was not closed properly.");
+        suggestion!("Try adding a double quote (\") at the end of the string literal.");
+        self.pos = self.src.len();
+        Lexeme {
+            kind: LexemeKind::Str(string),
+            span: Span::new(start, self.pos),
+        }
+    }
+
+    fn read_word(&mut self, start: usize) -> Lexeme {
+        let len = self.src[start..]
+            .find(|c: char| c.is_whitespace() || c == '"')
+            .unwrap_or(self.src.len() - start);
+        self.pos = start + len;
+        let word = &self.src[start..self.pos];
+        let looks_numeric = word
+            .trim_start_matches(['-', '+'])
+            .trim_start_matches('.')
+            .starts_with(|c: char| c.is_ascii_digit());
+        let kind = match word.parse::<f64>() {
+            Ok(n) if looks_numeric => LexemeKind::Number(n),
+            _ => LexemeKind::Ident(word.to_string()),
+        };
+        Lexeme {
+            kind,
+            span: Span::new(start, self.pos),
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Lexeme;
+
+    fn next(&mut self) -> Option<Lexeme> {
+        let start = self.offset();
+        if start >= self.src.len() {
+            self.pos = self.src.len();
+            return None;
+        }
+        if self.src[start..].starts_with('"') {
+            Some(self.read_string(start))
+        } else {
+            Some(self.read_word(start))
+        }
+    }
+}
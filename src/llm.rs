@@ -0,0 +1,264 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use eyre::{bail, eyre, Result};
+
+use crate::{config::Config, warn};
+
+/// Characters per token used to estimate how much of a table fits in a prompt.
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// A language model answering a single prompt with a single response.
+pub trait LlmBackend {
+    /// Short identifier of the backend, e.g. `chatgpt`.
+    fn name(&self) -> &str;
+    fn model(&self) -> &str;
+    fn complete(&self, prompt: &str) -> Result<String>;
+}
+
+/// Builds the backend selected by `llm_backend` (or `SPREAD_LLM_BACKEND`) in the config.
+///
+/// - `chatgpt` (default) talks to OpenAI and needs an OpenAI key.
+/// - `openai_compatible` posts to `{llm_base_url}/chat/completions`, the key is optional.
+/// - `mock` answers every prompt with `llm_mock_response`.
+pub fn backend_from_config(config: &Config) -> Result<Box<dyn LlmBackend>> {
+    let backend = config
+        .get("llm_backend", "SPREAD_LLM_BACKEND")
+        .unwrap_or_else(|| "chatgpt".to_string());
+    Ok(match backend.as_str() {
+        "chatgpt" => {
+            let Some(openai_key) = config.openai_key() else {
+                Config::missing_key_hint();
+                bail!("OpenAI key not found, AI features are unavailable.");
+            };
+            Box::new(ChatGptBackend::new(&openai_key)?)
+        }
+        "openai_compatible" | "http" => Box::new(OpenAiCompatibleBackend::new(
+            config
+                .get("llm_base_url", "SPREAD_LLM_BASE_URL")
+                .unwrap_or_else(|| "https://api.openai.com/v1".to_string()),
+            config
+                .get("llm_model", "SPREAD_LLM_MODEL")
+                .unwrap_or_else(|| "gpt-3.5-turbo".to_string()),
+            config.openai_key(),
+        )),
+        "mock" => Box::new(MockBackend::new(
+            config
+                .get("llm_mock_response", "SPREAD_LLM_MOCK_RESPONSE")
+                .unwrap_or_else(|| "None".to_string()),
+        )),
+        unknown => bail!(
+            "Unknown LLM backend \"{unknown}\", expected 'chatgpt', 'openai_compatible' or 'mock'."
+        ),
+    })
+}
+
+fn block_on<F: std::future::Future>(future: F) -> Result<F::Output> {
+    Ok(tokio::runtime::Runtime::new()?.block_on(future))
+}
+
+pub struct ChatGptBackend {
+    client: chatgpt::prelude::ChatGPT,
+    model: String,
+}
+
+impl ChatGptBackend {
+    pub fn new(openai_key: &str) -> Result<Self> {
+        Ok(ChatGptBackend {
+            client: chatgpt::prelude::ChatGPT::new(openai_key)?,
+            model: chatgpt::config::ChatGPTEngine::default().to_string(),
+        })
+    }
+}
+
+impl LlmBackend for ChatGptBackend {
+    fn name(&self) -> &str {
+        "chatgpt"
+    }
+    fn model(&self) -> &str {
+        &self.model
+    }
+    fn complete(&self, prompt: &str) -> Result<String> {
+        let response = block_on(self.client.send_message(prompt))??;
+        Ok(response.message().content.clone())
+    }
+}
+
+/// Any server speaking the OpenAI chat completions API, such as a local llama.cpp server.
+pub struct OpenAiCompatibleBackend {
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiCompatibleBackend {
+    pub fn new(base_url: String, model: String, api_key: Option<String>) -> Self {
+        OpenAiCompatibleBackend {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+        }
+    }
+}
+
+impl LlmBackend for OpenAiCompatibleBackend {
+    fn name(&self) -> &str {
+        "openai_compatible"
+    }
+    fn model(&self) -> &str {
+        &self.model
+    }
+    fn complete(&self, prompt: &str) -> Result<String> {
+        let url = format!("{}/chat/completions", self.base_url);
+        let body = serde_json::json!({
+            "model": self.model,
+            "messages": [{ "role": "user", "content": prompt }],
+        });
+        let response: serde_json::Value = block_on(async {
+            let mut request = reqwest::Client::new().post(&url).json(&body);
+            if let Some(api_key) = &self.api_key {
+                request = request.bearer_auth(api_key);
+            }
+            request.send().await?.error_for_status()?.json().await
+        })??;
+        response["choices"][0]["message"]["content"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| eyre!("Unexpected response from {url}: {response}"))
+    }
+}
+
+/// Directory cached responses are kept in, relative to the working directory.
+pub const CACHE_DIR: &str = ".spread-cache";
+
+/// Answers prompts it has seen before from files in a cache directory, asking `inner` otherwise.
+///
+/// Entries are keyed by backend, model and the exact prompt text.
+pub struct CachedBackend {
+    inner: Rc<dyn LlmBackend>,
+    dir: PathBuf,
+}
+
+impl CachedBackend {
+    pub fn new(inner: Rc<dyn LlmBackend>, dir: impl Into<PathBuf>) -> Self {
+        CachedBackend {
+            inner,
+            dir: dir.into(),
+        }
+    }
+
+    fn entry_path(&self, prompt: &str) -> PathBuf {
+        // FNV-1a, stable across runs and Rust versions unlike the std hasher
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for part in [self.inner.name(), self.inner.model(), prompt] {
+            for byte in part.bytes().chain(std::iter::once(0)) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        self.dir.join(format!("{hash:016x}.json"))
+    }
+
+    /// Removes every cached response in `dir`, returning how many were removed.
+    pub fn clear(dir: &Path) -> Result<usize> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => bail!("Could not read cache directory {}: {e}", dir.display()),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl LlmBackend for CachedBackend {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+    fn complete(&self, prompt: &str) -> Result<String> {
+        let path = self.entry_path(prompt);
+        let cached = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<serde_json::Value>(&text).ok());
+        if let Some(entry) = cached {
+            // the prompt is stored as well so a hash collision is never served
+            if entry["backend"] == self.name()
+                && entry["model"] == self.model()
+                && entry["prompt"] == prompt
+            {
+                if let Some(response) = entry["response"].as_str() {
+                    return Ok(response.to_string());
+                }
+            }
+        }
+        let response = self.inner.complete(prompt)?;
+        let entry = serde_json::json!({
+            "backend": self.name(),
+            "model": self.model(),
+            "prompt": prompt,
+            "response": response,
+        });
+        if let Err(e) = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&path, entry.to_string()))
+        {
+            warn!("Could not cache response in {}: {e}", path.display());
+        }
+        Ok(response)
+    }
+}
+
+/// Deterministic backend replaying queued responses, used for tests and offline use.
+pub struct MockBackend {
+    fallback: String,
+    responses: RefCell<VecDeque<String>>,
+    prompts: RefCell<Vec<String>>,
+}
+
+impl MockBackend {
+    /// A mock answering every prompt with `fallback` once queued responses run out.
+    pub fn new(fallback: impl Into<String>) -> Self {
+        MockBackend {
+            fallback: fallback.into(),
+            responses: RefCell::new(VecDeque::new()),
+            prompts: RefCell::new(Vec::new()),
+        }
+    }
+    pub fn queue(&self, response: impl Into<String>) {
+        self.responses.borrow_mut().push_back(response.into());
+    }
+    /// Prompts received so far, oldest first.
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts.borrow().clone()
+    }
+}
+
+impl LlmBackend for MockBackend {
+    fn name(&self) -> &str {
+        "mock"
+    }
+    fn model(&self) -> &str {
+        "mock"
+    }
+    fn complete(&self, prompt: &str) -> Result<String> {
+        self.prompts.borrow_mut().push(prompt.to_string());
+        Ok(self
+            .responses
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or_else(|| self.fallback.clone()))
+    }
+}
@@ -1,8 +1,13 @@
-ub mod autocorrect;
+pub mod autocorrect;
+pub mod closure;
 pub mod compiler;
+pub mod config;
+pub mod formula;
+pub mod lexer;
+pub mod llm;
+pub mod parser;
 pub mod table;
-pub mod closure;
-use clap::{command, crate_version, value_parser, Arg, Command};
+use clap::{command, crate_version, value_parser, Arg, ArgAction, Command};
 use compiler::*;
 use eyre::{bail, Result};
 use rustyline::error::ReadlineError;
@@ -45,6 +50,9 @@ macro_rules! exec_output {
             let file = File::open(input)?;
             let reader = BufReader::new(file);
             let mut interpreter = VM::new(String::new());
+            if $matches.get_flag("no-cache") {
+                interpreter.use_cache = false;
+            }
             for (index, line) in reader.lines().enumerate() {
                 let line = line?;
                 interpreter.code_text = line;
@@ -61,7 +69,12 @@ macro_rules! exec_output {
                     }
                 }
             }
-            std::fs::write(output, interpreter.save_to_string($output)?)?;
+            let with_title = !matches!($matches.try_get_one::<bool>("no-title"), Ok(Some(true)));
+            let output_text = match $output {
+                OutputType::Csv => interpreter.to_csv_with_title(with_title)?,
+                output_type => interpreter.save_to_string(output_type)?,
+            };
+            std::fs::write(output, output_text)?;
             return Ok(());
         }
     };
@@ -69,8 +82,22 @@ macro_rules! exec_output {
 
 fn main() -> Result<()> {
     let matches = command!()
-        .subcommand(input_output!("csv"))
+        .subcommand(
+            input_output!("csv").arg(
+                Arg::new("no-title")
+                    .long("no-title")
+                    .action(ArgAction::SetTrue)
+                    .help("Leave out the title line of each table"),
+            ),
+        )
         .subcommand(input_output!("html"))
+        .arg(
+            Arg::new("no-cache")
+                .long("no-cache")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Always ask the LLM backend instead of reusing cached responses"),
+        )
         .get_matches();
 
     exec_output!(matches, OutputType::Csv);
@@ -84,6 +111,9 @@ fn main() -> Result<()> {
     let _ = rl.load_history("history.txt");
 
     let mut interpreter = VM::new(String::new());
+    if matches.get_flag("no-cache") {
+        interpreter.use_cache = false;
+    }
     loop {
         let readline = rl.readline(">> ");
         match readline {
@@ -134,7 +164,7 @@ mod test {
             table1 create with title "overwritten once" with title "overwritten twice"
             "#;
         let mut vm = VM::new(code.to_string());
-        assert!(vm.tables.len() == 0);
+        assert!(vm.tables.is_empty());
         vm.interpret_next_line().unwrap();
         assert!(vm.tables.len() == 1);
         assert_eq!(vm.tables[0].title(), "Table 1");
@@ -161,7 +191,7 @@ mod test {
 
         vm.interpret_next_line().unwrap();
         vm.interpret_next_line().unwrap();
-        assert!(vm.tables[0].rows.len() == 0);
+        assert!(vm.tables[0].rows.is_empty());
 
         vm.interpret_next_line().unwrap();
         // assert_eq!(vm.tables[0].dimensions());
@@ -202,4 +232,1141 @@ mod test {
             }
         );
     }
+
+    #[test]
+    pub fn string_literals() {
+        let code = r#"t create with title "a  b" and headers "say \"hi\"" "x\\y"
+            t add_row "Smith, John" "two  spaces"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret_next_line().unwrap();
+        vm.interpret_next_line().unwrap();
+
+        assert_eq!(vm.tables[0].title(), "a  b");
+        assert_eq!(
+            vm.tables[0].rows[0],
+            Row {
+                cells: vec![
+                    Cell::String("Smith, John".to_string()),
+                    Cell::String("two  spaces".to_string()),
+                ]
+            }
+        );
+        assert_eq!(vm.tables[0].dimensions(), (1, 2));
+    }
+
+    #[test]
+    pub fn error_spans() {
+        let mut vm = VM::new("t  craete".to_string());
+        let error = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(error.starts_with("t  craete\n   ^^^^^^\n"));
+
+        let mut vm = VM::new("missing view".to_string());
+        let error = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(error.starts_with("missing view\n^^^^^^^\n"));
+
+        let mut vm = VM::new("t create\nt add_row \"a\" b".to_string());
+        vm.interpret_next_line().unwrap();
+        let error = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(error.starts_with("t add_row \"a\" b\n              ^\n"));
+    }
+
+    #[test]
+    pub fn csv_output() {
+        let code = r#"t create with title "people" and headers "name" "quote"
+            t add_row "Smith, John" "said \"hi\""
+            t add_row "solo"
+            t add_row "plain" "12"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+
+        assert_eq!(
+            vm.tables[0].to_csv().unwrap(),
+            "people\r\nname,quote\r\n\"Smith, John\",\"said \"\"hi\"\"\"\r\nsolo,\r\nplain,12\r\n"
+        );
+        assert_eq!(
+            vm.tables[0].to_csv_with_title(false).unwrap(),
+            "name,quote\r\n\"Smith, John\",\"said \"\"hi\"\"\"\r\nsolo,\r\nplain,12\r\n"
+        );
+
+        let mut t = Table::new("t".to_string());
+        t.extend_headers(vec![Cell::String("a".to_string())]);
+        t.append_row_from(vec!["line 1\nline 2"]);
+        assert_eq!(
+            t.to_csv_with_title(false).unwrap(),
+            "a\r\n\"line 1\nline 2\"\r\n"
+        );
+    }
+
+    #[test]
+    pub fn load_csv() {
+        let path = std::env::temp_dir().join("spread_lang_load_csv.csv");
+        std::fs::write(
+            &path,
+            "fruit;weight\r\n\"Smith; \"\"Apple\"\"\";15\r\n\r\nmango;\"line 1\nline 2\"\r\npapaya\n",
+        )
+        .unwrap();
+        let code = format!(
+            "t load \"{path}\" with headers and delimiter \";\"\nraw load \"{path}\" with title \"raw\"",
+            path = path.display()
+        );
+        let mut vm = VM::new(code);
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.tables[0].dimensions(), (3, 2));
+        assert_eq!(
+            vm.tables[0].rows[0],
+            Row {
+                cells: vec![
+                    Cell::String("Smith; \"Apple\"".to_string()),
+                    Cell::Number(15.0)
+                ]
+            }
+        );
+        assert_eq!(
+            vm.tables[0].rows[1].cells[1],
+            Cell::String("line 1\nline 2".to_string())
+        );
+        assert_eq!(vm.tables[0].rows[2].cells[1], Cell::Empty);
+
+        assert_eq!(vm.tables[1].title(), "raw");
+        assert_eq!(vm.tables[1].dimensions(), (5, 1));
+        assert!(parse_csv("a,\"b", ',').is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn closure_logic_and_comparison() {
+        let code = r#"t create with headers "a" "b"
+            t add_row "12" "10"
+            t add_row "true" "false"
+            t add_row
+            t add_row
+            t add_row
+            and_num define 2,0!reg!1;raw!reg!0!~cell(0,0) ~cell(0,1) &;
+            xor_num define 2,1!reg!1;raw!reg!0!~cell(0,0) ~cell(0,1) ^;
+            or_bool define 3,0!reg!1;raw!reg!0!~cell(1,0) ~cell(1,1) |;
+            count_big define 3,1!reg!1;each!reg!0!~op ~cell 11 > 0 + +;
+            not_ten define 4,0!reg!1;raw!reg!0!~cell(0,1) 10 !=;
+            t apply and_num 0 0
+            t apply xor_num 0 0
+            t apply or_bool 0 0
+            t apply count_big 0 0 0 1
+            t apply not_ten 0 0
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+
+        let t = &vm.tables[0];
+        assert_eq!(t.get_cell(2, 0).unwrap(), &Cell::Number(8.0));
+        assert_eq!(t.get_cell(2, 1).unwrap(), &Cell::Number(6.0));
+        assert_eq!(t.get_cell(3, 0).unwrap(), &Cell::Boolean(true));
+        assert_eq!(t.get_cell(3, 1).unwrap(), &Cell::Number(1.0));
+        assert_eq!(t.get_cell(4, 0).unwrap(), &Cell::Boolean(false));
+
+        assert!((Cell::Number(1.5) & Cell::Number(1.0)).is_err());
+        assert!((Cell::String("x".to_string()) | Cell::Boolean(true)).is_err());
+        let err = (Cell::Empty & Cell::String("x".to_string())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Bitwise operations can't be applied on string \"x\"."
+        );
+        assert!((Cell::String("x".to_string()) ^ Cell::Empty).is_err());
+        assert_eq!(
+            (Cell::Empty | Cell::Number(6.0)).unwrap(),
+            Cell::Number(6.0)
+        );
+        assert_eq!(
+            (Cell::Boolean(true) & Cell::Empty).unwrap(),
+            Cell::Boolean(true)
+        );
+    }
+
+    #[test]
+    pub fn closure_conditionals() {
+        let code = r#"t create with headers "a" "b"
+            t add_row "5" "5"
+            t add_row "-3" "-3"
+            t add_row "12" "12"
+            sum_positive define auto!reg!1;each!reg!0!~op ~cell 0 > ~cell 0 ? +;
+            count_big define auto!reg!1;each!reg!0!if!~cell 10 >=!~op 1 +!~op;
+            clamp define 2,0!reg!1;raw!reg!0!if!~cell(2,0) 10 >!10!~cell(2,0);
+            t apply sum_positive 0 0 2 0
+            t apply count_big 0 1 2 1
+            t apply clamp 2 0
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+
+        let t = &vm.tables[0];
+        assert_eq!(t.get_cell(3, 0).unwrap(), &Cell::Number(17.0));
+        assert_eq!(t.get_cell(3, 1).unwrap(), &Cell::Number(1.0));
+        assert_eq!(t.get_cell(2, 0).unwrap(), &Cell::Number(10.0));
+    }
+
+    #[test]
+    pub fn closure_calls() {
+        let code = r#"t create with headers "a"
+            t add_row "4"
+            t add_row "8"
+            t add_row "12"
+            sum define auto!reg!1;each!reg!0!~op ~cell +;
+            count define auto!reg!1;each!reg!0!~op 1 +;
+            avg define auto!reg!1;raw!reg!0!~fn sum ~fn count /;
+            t apply avg 0 0 2 0
+            ping define auto!reg!1;raw!reg!0!~fn pong;
+            pong define auto!reg!1;raw!reg!0!~fn ping;
+            lost define auto!reg!1;raw!reg!0!~fn missing;
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..8 {
+            vm.interpret_next_line().unwrap();
+        }
+        assert_eq!(vm.tables[0].get_cell(3, 0).unwrap(), &Cell::Number(8.0));
+
+        vm.interpret().unwrap();
+        vm.code_text = "t apply ping 0 0".to_string();
+        vm.pointer = 0;
+        let error = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(error.contains("ping -> pong -> ping"));
+
+        vm.code_text = "t apply lost 0 0".to_string();
+        vm.pointer = 0;
+        assert!(vm.interpret_next_line().is_err());
+    }
+
+    #[test]
+    pub fn closure_sections() {
+        let code = r#"t create with headers "a" "b"
+            t add_row "4" "1"
+            t add_row
+            increment define 0,1;raw!acc!~cell 1 +
+            sum define auto;each!acc!~op ~cell +;
+            t apply increment 0 1
+            t apply sum 0 0
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+        assert_eq!(vm.tables[0].get_cell(0, 1).unwrap(), &Cell::Number(2.0));
+        assert_eq!(vm.tables[0].get_cell(1, 0).unwrap(), &Cell::Number(4.0));
+
+        for malformed in [
+            "c define auto!reg!1",
+            "c define a;b;c;d",
+            "c define",
+            "c define auto;each!reg!0!~op ~cell +;",
+            "c define reg!1;each!acc!~op ~cell +;",
+            "c define auto!reg!1;each!reg!0;",
+            "c define auto!reg!x;each!acc!~cell;",
+            "c define auto;each!acc!~cell!~op;",
+        ] {
+            let mut vm = VM::new(malformed.to_string());
+            assert!(vm.interpret_next_line().is_err(), "{malformed}");
+            assert!(!vm.closure_idx.contains_key("c"));
+        }
+    }
+
+    #[test]
+    pub fn closure_compile_errors() {
+        for malformed in [
+            "c define auto!reg!1;each!reg!0!~op +;",
+            "c define auto!reg!1;each!reg!0!~op ~cel +;",
+            "c define auto!reg!1;each!reg!0!~op ~cell 1;",
+            "c define auto!reg!1;each!reg!0!~cell(1) ~op +;",
+            "c define auto!reg!1;each!reg!0!if!~cell 0 >!~op ~cell +!?;",
+        ] {
+            let mut vm = VM::new(malformed.to_string());
+            assert!(vm.interpret_next_line().is_err(), "{malformed}");
+        }
+
+        let mut vm = VM::new("c define ~cell.x 1 +,0;raw!acc!~fn later 1 ~cell ?;".to_string());
+        vm.interpret_next_line().unwrap();
+        assert!(matches!(
+            vm.closures[vm.closure_idx["c"]].prescript.output,
+            Some(crate::closure::OutputCell::At(_, _))
+        ));
+    }
+
+    #[test]
+    pub fn runtime_config() {
+        let mut config = crate::config::Config::default();
+        config.merge(
+            "# comment\nopenai_key = \"sk-file\"\nnot a setting\nopenai_key = ignored\n",
+            std::path::Path::new("spread-lang.conf"),
+        );
+        assert_eq!(
+            config.get("openai_key", "SPREAD_LANG_TEST_UNSET"),
+            Some("sk-file".to_string())
+        );
+        assert_eq!(config.get("base_url", "SPREAD_LANG_TEST_UNSET"), None);
+    }
+
+    #[test]
+    pub fn mock_llm_backend() {
+        use crate::llm::{LlmBackend, MockBackend};
+        use std::rc::Rc;
+
+        let code = r#"fruits create with headers "fruit" "weight"
+            fruits add_row "apple" "3"
+            fruits prompt "Which fruit is the heaviest?"
+            "#;
+        let mock = Rc::new(MockBackend::new("apple"));
+        let mut vm = VM::new(code.to_string());
+        vm.llm = Some(mock.clone());
+        vm.interpret().unwrap();
+        let prompts = mock.prompts();
+        assert_eq!(prompts.len(), 1);
+        assert!(prompts[0].contains("apple,3"));
+        assert!(prompts[0].ends_with("Which fruit is the heaviest?"));
+
+        mock.queue("Bargraph\nx = 1\ny = 2");
+        let suggestion = vm.tables[0].plot_suggestion(mock.as_ref()).unwrap();
+        assert_eq!(suggestion, ResponsePlotType::Bargraph((0, 1)));
+        assert_eq!(mock.name(), "mock");
+    }
+
+    #[test]
+    pub fn explicit_plot() {
+        let path = std::env::temp_dir().join("spread-lang-explicit-plot.html");
+        let code = format!(
+            r#"fruits create with headers "fruit" "weight"
+            fruits add_row "apple" "3"
+            fruits add_row "pear" "5"
+            fruits plot bar x "fruit" y 2 to "{}"
+            fruits plot hist col "colour"
+            fruits plot donut x 1
+            fruits plot scatter x 1
+            fruits plot bar x 1 y 3
+            "#,
+            path.display()
+        );
+        let mut vm = VM::new(code);
+        vm.interpret_next_line().unwrap();
+        vm.interpret_next_line().unwrap();
+        vm.interpret_next_line().unwrap();
+        let _ = std::fs::remove_file(&path);
+        vm.interpret_next_line().unwrap();
+        let html = std::fs::read_to_string(&path).unwrap();
+        assert!(html.contains("\"type\": \"bar\""));
+        assert!(html.contains("weight"));
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("No column named \"colour\""));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("is not a plot kind"));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("needs a column for axis y"));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("out of bounds"));
+    }
+
+    #[test]
+    pub fn pie_and_line_plots() {
+        let path = std::env::temp_dir().join("spread-lang-pie-line-plot.html");
+        let code = format!(
+            r#"sales create with headers "month" "region" "north" "south"
+            sales add_row "jan" "north" "3" "4"
+            sales add_row "feb" "south" "5" ""
+            sales add_row "mar" "north" "2" "1"
+            sales plot pie x "region" to "{path}"
+            sales plot pie x "region" y "north" to "{path}"
+            sales plot line x "month" y "north" "south" to "{path}"
+            sales plot pie x "region" y "month"
+            "#,
+            path = path.display()
+        );
+        let mut vm = VM::new(code);
+        for _ in 0..4 {
+            vm.interpret_next_line().unwrap();
+        }
+        vm.interpret_next_line().unwrap();
+        let html: String = std::fs::read_to_string(&path)
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        assert!(html.contains("\"labels\":[\"north\",\"south\"],\"values\":[2.0,1.0]"));
+        vm.interpret_next_line().unwrap();
+        let html: String = std::fs::read_to_string(&path)
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        assert!(html.contains("\"values\":[5.0,5.0]"));
+        vm.interpret_next_line().unwrap();
+        let html: String = std::fs::read_to_string(&path)
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        assert_eq!(html.matches("\"mode\":\"lines\"").count(), 2);
+        assert!(html.contains("\"name\":\"south\""));
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Pie chart values must be numbers"));
+        assert_eq!(
+            "Piechart\nx = 2".parse::<ResponsePlotType>(),
+            Ok(ResponsePlotType::Piechart((1, None)))
+        );
+    }
+
+    #[test]
+    pub fn plot_reply_parsing() {
+        use crate::llm::MockBackend;
+
+        let parse = |reply: &str| reply.parse::<ResponsePlotType>();
+        assert_eq!(
+            parse("Scatter\nx = 1\ny = 2"),
+            Ok(ResponsePlotType::Scatterplot((0, 1)))
+        );
+        assert_eq!(
+            parse("```json\n{\"plot\": \"histogram\", \"x\": 2, \"y\": null}\n```"),
+            Ok(ResponsePlotType::Histogram(1))
+        );
+        assert_eq!(
+            parse("Plot: Bar graph\ny: 3\nx: 1"),
+            Ok(ResponsePlotType::Bargraph((0, 2)))
+        );
+        assert_eq!(
+            parse("Data Insufficient"),
+            Ok(ResponsePlotType::DataInsufficient)
+        );
+        // a plot without usable columns is no answer either
+        assert_eq!(parse("Bargraph\nx = 0"), Err(()));
+        assert_eq!(parse("I would draw a chart"), Err(()));
+
+        let code = r#"fruits create with headers "fruit" "weight"
+            fruits add_row "apple" "3"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+        let table = &vm.tables[0];
+        let mock = MockBackend::new("{\"plot\": \"scatter\", \"x\": 1, \"y\": 2}");
+        assert_eq!(
+            table.plot_suggestion(&mock).unwrap(),
+            ResponsePlotType::Scatterplot((0, 1))
+        );
+        // out of range or missing columns and unreadable replies fall back to the local choice
+        mock.queue("{\"plot\": \"bar\", \"x\": 1, \"y\": 7}");
+        mock.queue("Sure! A nice chart would work.");
+        mock.queue("Bargraph\nx = 0");
+        assert_eq!(
+            table.plot_suggestion(&mock).unwrap(),
+            ResponsePlotType::Bargraph((0, 1))
+        );
+        assert_eq!(
+            table.plot_suggestion(&mock).unwrap(),
+            ResponsePlotType::Bargraph((0, 1))
+        );
+        assert_eq!(
+            table.plot_suggestion(&mock).unwrap(),
+            ResponsePlotType::Bargraph((0, 1))
+        );
+    }
+
+    #[test]
+    pub fn local_plot_recommendation() {
+        let code = r#"empty create with headers "a" "b"
+            fruits create with headers "fruit" "weight"
+            fruits add_row "apple" "3"
+            fruits add_row "pear" "5"
+            orders create with headers "region" "amount"
+            orders add_row "north" "3"
+            orders add_row "south" "5"
+            orders add_row "north" "2"
+            points create with headers "x" "y"
+            points add_row "1" "2"
+            points add_row "2" "4"
+            heights create with headers "height" "note"
+            heights add_row "170" ""
+            heights add_row "182" ""
+            names create with headers "name"
+            names add_row "ann"
+            names add_row "bob"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+        let recommended: Vec<_> = vm.tables.iter().map(Table::recommended_plot).collect();
+        assert_eq!(
+            recommended,
+            vec![
+                ResponsePlotType::DataInsufficient,
+                ResponsePlotType::Bargraph((0, 1)),
+                ResponsePlotType::Piechart((0, Some(1))),
+                ResponsePlotType::Scatterplot((0, 1)),
+                ResponsePlotType::Histogram(0),
+                ResponsePlotType::None,
+            ]
+        );
+    }
+
+    #[test]
+    pub fn multi_table_prompt() {
+        use crate::llm::MockBackend;
+        use std::rc::Rc;
+
+        let code = r#"fruits create with title "Fruits" and headers "fruit" "weight"
+            fruits add_row "apple" "3"
+            prices create with title "Prices" and headers "fruit" "price"
+            prices add_row "apple" "1.5"
+            fruits,prices prompt "What does an apple cost per kilo?"
+            fruits,pricez prompt "typo"
+            fruits,prices view
+            "#;
+        let mock = Rc::new(MockBackend::new("0.5"));
+        let mut vm = VM::new(code.to_string());
+        vm.llm = Some(mock.clone());
+        for _ in 0..5 {
+            vm.interpret_next_line().unwrap();
+        }
+        let prompt = &mock.prompts()[0];
+        assert!(prompt.contains("My tables are as follows"));
+        assert!(prompt.contains("Table name:Fruits\r\nfruit,weight\r\napple,3\r\n"));
+        assert!(prompt.contains("Table name:Prices\r\nfruit,price\r\napple,1.5\r\n"));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("No table found with name \"pricez\""));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Only prompt can reference several tables"));
+
+        let mut big = Table::new("big".to_string());
+        big.extend_headers(vec![Cell::String("n".to_string())]);
+        for n in 0..100 {
+            big.append_row(format!("{n:03}"));
+        }
+        let sampled = big.to_prompt_csv(200);
+        assert!(sampled.len() <= 200);
+        assert!(sampled.starts_with("big\r\nn\r\n0\r\n"));
+        assert!(sampled.ends_with("rows shown, sampled evenly\r\n"));
+        assert_eq!(big.to_prompt_csv(10_000).lines().count(), 102);
+    }
+
+    #[test]
+    pub fn prompt_answers_into_table() {
+        use crate::llm::MockBackend;
+        use std::rc::Rc;
+
+        let code = r#"reviews create with headers "review" "stars"
+            reviews add_row "great" "5"
+            reviews add_row "awful" "1"
+            reviews fill_column "sentiment" from prompt "Is this review positive or negative?"
+            reviews prompt_into B2 "How many stars would you give it?"
+            reviews prompt_into A5 "out of range"
+            reviews fill_column "sentiment" prompt "missing from"
+            "#;
+        let mock = Rc::new(MockBackend::new("unused"));
+        mock.queue("positive");
+        mock.queue("\"negative\"\nThe review is unhappy.");
+        mock.queue("2");
+        let mut vm = VM::new(code.to_string());
+        vm.llm = Some(mock.clone());
+        for _ in 0..5 {
+            vm.interpret_next_line().unwrap();
+        }
+        let prompts = mock.prompts();
+        assert_eq!(prompts.len(), 3);
+        assert!(prompts[1].contains("review,stars,sentiment\r\nawful,1,\r\n"));
+        assert!(!prompts[1].contains("great"));
+        assert_eq!(
+            vm.to_csv_with_title(false).unwrap(),
+            "review,stars,sentiment\r\ngreat,5,positive\r\nawful,2,negative\r\n"
+        );
+        assert!(vm.interpret_next_line().is_err());
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Expected \"from\" here."));
+        assert_eq!(mock.prompts().len(), 3);
+    }
+
+    #[test]
+    pub fn llm_response_cache() {
+        use crate::llm::{CachedBackend, LlmBackend, MockBackend};
+        use std::rc::Rc;
+
+        let dir = std::env::temp_dir().join("spread-lang-llm-cache-test");
+        CachedBackend::clear(&dir).unwrap();
+        let mock = Rc::new(MockBackend::new("fallback"));
+        mock.queue("first");
+        let cached = CachedBackend::new(mock.clone(), &dir);
+        assert_eq!(cached.complete("question").unwrap(), "first");
+        assert_eq!(cached.complete("question").unwrap(), "first");
+        assert_eq!(cached.complete("other question").unwrap(), "fallback");
+        assert_eq!(mock.prompts().len(), 2);
+
+        // a fresh backend over the same directory reuses the files
+        let again = CachedBackend::new(Rc::new(MockBackend::new("changed")), &dir);
+        assert_eq!(again.complete("question").unwrap(), "first");
+        assert_eq!(CachedBackend::clear(&dir).unwrap(), 2);
+        assert_eq!(again.complete("question").unwrap(), "changed");
+        CachedBackend::clear(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn formulas() {
+        use crate::formula::{column_index, column_name, CellRef};
+
+        assert_eq!(column_index("AA"), Some(26));
+        assert_eq!(column_name(27), "AB");
+        assert_eq!(CellRef::parse("b3"), Some(CellRef { row: 2, col: 1 }));
+        assert_eq!(CellRef::parse("B0"), None);
+
+        let code = r#"sales create with headers "item" "amount" "total"
+            sales add_row "pen" "2" "=SUM(B1:B3)"
+            sales add_row "ink" "3" "=C1 / COUNT(B1:B3) + -1"
+            sales add_row "pad" "5" "=AVG(B1:B3) >= MAX(B1, 4)"
+            sales add_row "bad" "=A1 * 2" "=B9"
+            loop create with headers "a" "b"
+            loop add_row "=B1 + 1" "=A1 + 1"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..5 {
+            vm.interpret_next_line().unwrap();
+        }
+        assert_eq!(
+            vm.tables[0].to_csv_with_title(false).unwrap(),
+            "item,amount,total\r\npen,2,10\r\nink,3,2.3333333333333335\r\npad,5,false\r\nbad,#VALUE!,#REF!\r\n"
+        );
+        // changing a referenced cell recomputes the formulas reading it
+        *vm.tables[0].get_cell_mut(0, 1).unwrap() = Cell::Number(12_f64);
+        vm.tables[0].recalculate().unwrap();
+        assert_eq!(
+            vm.tables[0].get_cell(0, 2).unwrap().value(),
+            &Cell::Number(20_f64)
+        );
+        assert_eq!(
+            vm.tables[0].get_cell(1, 2).unwrap().to_string(),
+            (20_f64 / 3_f64 - 1_f64).to_string()
+        );
+
+        vm.interpret_next_line().unwrap();
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Circular reference in table loop: A1 -> B1 -> A1"));
+        assert_eq!(vm.tables[1].get_cell(0, 1).unwrap().to_string(), "#CYCLE!");
+    }
+
+    #[test]
+    pub fn formula_errors() {
+        let code = r#"t create with headers "text" "n" "result"
+            t add_row "x" "4" "=SUM(A1:A1)"
+            t add_row "" "0" "=B1 / B2"
+            t add_row "" "" "=AVG(A1:A1)"
+            t add_row "" "" "=A1 * 2"
+            t add_row "" "" "=Z9"
+            t add_row "" "" "=MIN(A1:A1)"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+        let results: Vec<String> = (0..6)
+            .map(|row| vm.tables[0].get_cell(row, 2).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            results,
+            ["0", "#DIV/0!", "#DIV/0!", "#VALUE!", "#REF!", "#VALUE!"]
+        );
+    }
+
+    #[test]
+    pub fn a1_addressing() {
+        let code = r#"t create with headers "item" "weight (kg)" "total"
+            t add_row "pen" "2" ""
+            t add_row "ink" "3" ""
+            t add_row "pad" "5" ""
+            sum define auto;each!acc!~op ~cell +;
+            t apply sum B:B
+            t apply sum "weight (kg)"
+            total define C1;raw!acc!~cell(B2) ~cell(2,"weight (kg)") +;
+            t apply total A1:B3
+            double define 1,"total";raw!acc!~cell(0,"total") 2 *;
+            t apply double B1
+            t apply sum "missing"
+            t apply sum B2:
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..11 {
+            vm.interpret_next_line().unwrap();
+        }
+        let table = &vm.tables[0];
+        // `B:B` wrote below the column, the header form then saw that row too
+        assert_eq!(table.get_cell(3, 1).unwrap(), &Cell::Number(10.0));
+        assert_eq!(table.get_cell(4, 1).unwrap(), &Cell::Number(20.0));
+        assert_eq!(table.get_cell(0, 2).unwrap(), &Cell::Number(8.0));
+        assert_eq!(table.get_cell(1, 2).unwrap(), &Cell::Number(16.0));
+        assert_eq!(table.parse_address("A2:C3").unwrap(), ((1, 0), (2, 2)));
+        assert_eq!(table.parse_address("C3:A2").unwrap(), ((1, 0), (2, 2)));
+        assert_eq!(table.parse_address("A3:C2").unwrap(), ((1, 0), (2, 2)));
+        assert_eq!(table.parse_address("C:B").unwrap(), ((0, 1), (4, 2)));
+        assert_eq!(table.parse_address("total").unwrap(), ((0, 2), (4, 2)));
+        assert_eq!(Table::address(2, 27), "AB3");
+        assert_eq!(Table::column_letters(25), "Z");
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("No column named \"missing\""));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("\"B2:\" is not a cell range."));
+    }
+
+    #[test]
+    pub fn row_and_cell_edits() {
+        let code = r#"t create with headers "item" "qty" "total"
+            t add_row "pen" "2" "=SUM(B1:B3)"
+            t add_row "ink" "3"
+            t add_row "pad" "5"
+            t set B2 "4"
+            t set A3 "paper"
+            t insert_row 2 "cap" "1"
+            t insert_row 5 "nib" "6"
+            t update_row 1 "pencil" "7" "=SUM(B1:B4)"
+            t delete_row 3
+            t delete_row 9
+            t set D1 "x"
+            t insert_row 6 "late"
+            t delete_row 0
+            t set 1 1 "x"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..10 {
+            vm.interpret_next_line().unwrap();
+        }
+        let table = &vm.tables[0];
+        assert_eq!(table.dimensions(), (4, 3));
+        let items: Vec<String> = (0..4)
+            .map(|row| table.get_cell(row, 0).unwrap().to_string())
+            .collect();
+        assert_eq!(items, ["pencil", "cap", "paper", "nib"]);
+        // B1:B4 shrank to B1:B3 when "ink" was deleted from it
+        assert_eq!(table.get_cell(0, 2).unwrap().value(), &Cell::Number(13.0));
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "Row 9 is out of range, table t has 4 rows.");
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "Column D is out of range, table t has 3 columns.");
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "Row 6 is out of range, table t has 4 rows.");
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Expected a 1-based row number."));
+        // `row col` pairs are only read by `apply`, where they are 0-based
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Expected a single cell such as B2."));
+    }
+
+    #[test]
+    pub fn column_operations() {
+        let code = r#"t create with headers "item" "qty"
+            t add_row "pen" "2"
+            t add_row "ink" "3"
+            t headers "price"
+            t set C1 "1.5"
+            t set C2 "4"
+            t add_column "total" "=B1 * C1"
+            t add_column "store" "main"
+            t rename_column 5 "shop"
+            t move_column "total" 1
+            t drop_column "qty"
+            t reorder_columns "shop" 3
+            t drop_column "price"
+            t rename_column "item" "shop"
+            t move_column "item" 9
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..12 {
+            vm.interpret_next_line().unwrap();
+        }
+        let table = &vm.tables[0];
+        let headers = ["shop", "price", "total", "item"].map(|name| table.header_index(name));
+        assert_eq!(headers, [Some(0), Some(1), Some(2), Some(3)]);
+        // dropping "qty" left the totals without their cells
+        assert_eq!(table.get_cell(1, 2).unwrap(), &Cell::String("#REF!".into()));
+        assert_eq!(table.get_cell(1, 0).unwrap(), &Cell::String("main".into()));
+        assert!(table.get_column(3).is_ok());
+
+        vm.interpret_next_line().unwrap();
+        let table = &vm.tables[0];
+        assert_eq!(table.dimensions(), (2, 3));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "Column \"shop\" already exists in table t.");
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "Column I is out of range, table t has 3 columns.");
+    }
+
+    #[test]
+    pub fn filled_down_column_formulas() {
+        let code = r#"t create with headers "qty" "price"
+            t add_row "2" "1.5"
+            t add_row "3" "4"
+            t add_column "total" "=A1 * B1"
+            t move_column "total" 1
+            t add_column "share" "=A1 / SUM(A$1:A$2)"
+            t add_column "window" "=SUM(A1:A2)"
+            t insert_row 1 "" "" ""
+            "#;
+        let mut vm = VM::new(code.to_string());
+        let source = |table: &Table, row, col| match table.get_cell(row, col).unwrap() {
+            Cell::Formula(formula) => formula.source().to_string(),
+            cell => panic!("expected a formula, found {cell}"),
+        };
+        for _ in 0..7 {
+            vm.interpret_next_line().unwrap();
+        }
+        let table = &vm.tables[0];
+        assert_eq!(table.get_cell(0, 0).unwrap().value(), &Cell::Number(3.0));
+        assert_eq!(table.get_cell(1, 0).unwrap().value(), &Cell::Number(12.0));
+        assert_eq!(source(table, 1, 0), "B2 * C2");
+        // `$` keeps the rows of the total in place, the relative window runs past the data
+        let values: Vec<String> = (0..2)
+            .flat_map(|row| (3..5).map(move |col| (row, col)))
+            .map(|(row, col)| table.get_cell(row, col).unwrap().to_string())
+            .collect();
+        assert_eq!(values, ["0.2", "15", "0.8", "#REF!"]);
+        assert_eq!(source(table, 1, 3), "A2 / SUM(A$1:A$2)");
+        assert_eq!(source(table, 1, 4), "SUM(A2:A3)");
+
+        // inserting a row still moves fixed references along with their cells
+        vm.interpret_next_line().unwrap();
+        assert_eq!(source(&vm.tables[0], 2, 3), "A3 / SUM(A$2:A$3)");
+    }
+
+    #[test]
+    pub fn sort_and_filter() {
+        let code = r#"t create with headers "fruit" "weight" "origin"
+            t add_row "apple" "12" "b"
+            t add_row "mango" "" "a"
+            t add_row "kiwi" "9" "a"
+            t add_row "plum" "12" "a"
+            t add_row "fig" "n/a" "b"
+            t sort by "weight" desc
+            heavy = t filter "weight" > 10
+            t sort by "origin" 2
+            t filter "origin" = "a" and 2 <= 10
+            t sort by "colour"
+            t filter "weight" ~ 3
+            x = t sort by 1
+            "#;
+        let mut vm = VM::new(code.to_string());
+        let fruits = |table: &Table| -> Vec<String> {
+            (0..table.dimensions().0)
+                .map(|row| table.get_cell(row, 0).unwrap().to_string())
+                .collect()
+        };
+        for _ in 0..7 {
+            vm.interpret_next_line().unwrap();
+        }
+        // text goes before numbers when descending, ties keep their order, empty stays last
+        assert_eq!(
+            fruits(&vm.tables[0]),
+            ["fig", "apple", "plum", "kiwi", "mango"]
+        );
+        vm.interpret_next_line().unwrap();
+        let heavy = &vm.tables[vm.tables_idx["heavy"]];
+        assert_eq!(fruits(heavy), ["apple", "plum"]);
+
+        vm.interpret_next_line().unwrap();
+        assert_eq!(
+            fruits(&vm.tables[0]),
+            ["kiwi", "plum", "mango", "apple", "fig"]
+        );
+        vm.interpret_next_line().unwrap();
+        assert_eq!(fruits(&vm.tables[0]), ["kiwi", "mango"]);
+        assert_eq!(fruits(&vm.tables[vm.tables_idx["heavy"]]).len(), 2);
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("No column named \"colour\""));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Expected a comparison"));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Only filter, group_by, pivot and join can create a new table"));
+    }
+
+    #[test]
+    pub fn group_by_and_pivot() {
+        let code = r#"sales create with headers "id" "region" "month" "amount"
+            sales add_row "a1" "north" "jan" "10"
+            sales add_row "a2" "south" "jan" "4"
+            sales add_row "a3" "north" "feb" "6"
+            sales add_row "a4" "north" "jan" "1.5"
+            sales add_row "" "south" "feb" "n/a"
+            summary = sales group_by "region" aggregate sum "amount" count "id" avg 4
+            months = sales pivot "region" by "month" aggregate sum "amount"
+            sales group_by "region" aggregate sum "amount"
+            summary = sales group_by "region" aggregate median "amount"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..8 {
+            vm.interpret_next_line().unwrap();
+        }
+        let summary = &vm.tables[vm.tables_idx["summary"]];
+        assert_eq!(summary.dimensions(), (2, 4));
+        assert_eq!(summary.header_index("count of id"), Some(2));
+        let row = |table: &Table, row| -> Vec<String> {
+            (0..table.dimensions().1)
+                .map(|col| table.get_cell(row, col).unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(row(summary, 0), ["north", "17.5", "3", "5.833333333333333"]);
+        assert_eq!(row(summary, 1), ["south", "4", "1", "4"]);
+
+        let months = &vm.tables[vm.tables_idx["months"]];
+        assert_eq!(months.header_index("feb"), Some(2));
+        assert_eq!(row(months, 0), ["north", "11.5", "6"]);
+        // south only has text for february, which sums to 0 as in `apply sum` and =SUM()
+        assert_eq!(row(months, 1), ["south", "4", "0"]);
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("name it as in \"new_table = sales ...\""));
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("Expected an aggregate"));
+    }
+
+    #[test]
+    pub fn join_and_append() {
+        let code = r#"orders create with headers "order" "customer_id" "name"
+            orders add_row "o1" "2" "lamp"
+            orders add_row "o2" "1" "desk"
+            orders add_row "o3" "9" "rug"
+            orders add_row "o4" "2" "mat"
+            customers create with headers "customer_id" "name"
+            customers add_row "1" "Asha"
+            customers add_row "2" "Ravi"
+            customers add_row "3" "Mei"
+            inner = orders join customers on "customer_id"
+            left = orders left_join customers on "customer_id"
+            outer = orders outer_join customers on 2 = 1
+            more create with headers "name" "customer_id" "order"
+            more add_row "tray" "3" "o5"
+            orders append more
+            orders append customers
+            orders join customers on "customer_id"
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..15 {
+            vm.interpret_next_line().unwrap();
+        }
+        let column = |name: &str, col| -> Vec<String> {
+            let table = &vm.tables[vm.tables_idx[name]];
+            (0..table.dimensions().0)
+                .map(|row| table.get_cell(row, col).unwrap().to_string())
+                .collect()
+        };
+        let inner = &vm.tables[vm.tables_idx["inner"]];
+        assert_eq!(inner.dimensions(), (3, 4));
+        assert_eq!(inner.header_index("name (orders)"), Some(2));
+        assert_eq!(inner.header_index("name (customers)"), Some(3));
+        assert_eq!(column("inner", 3), ["Ravi", "Asha", "Ravi"]);
+        assert_eq!(column("left", 3), ["Ravi", "Asha", "", "Ravi"]);
+        assert_eq!(column("outer", 1), ["2", "1", "9", "2", "3"]);
+        assert_eq!(column("outer", 0), ["o1", "o2", "o3", "o4", ""]);
+        // columns of the appended table are matched by header
+        assert_eq!(column("orders", 0)[4], "o5");
+        assert_eq!(column("orders", 2)[4], "tray");
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Headers of orders and customers don't match, orders only has \"order\"."
+        );
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert!(err.contains("name it as in \"new_table = orders ...\""));
+    }
+
+    #[test]
+    pub fn builtin_closures() {
+        let code = r#"t create with headers "a" "b" "c" "d" "e" "f" "g" "h" "i"
+            t add_row "4" "4" "4" "4" "4" "4" "4" "4" "ab"
+            t add_row "x" "x" "x" "x" "x" "x" "x" "x" ""
+            t add_row "1" "1" "1" "1" "1" "1" "1" "1" "2"
+            t add_row "7" "7" "7" "7" "7" "7" "7" "7" "cd"
+            t add_row ""
+            t apply sum A1:A4
+            t apply count B1:B4
+            t apply avg C1:C4
+            t apply min D1:D4
+            t apply max E1:E4
+            t apply median F1:F4
+            t apply stddev G1:G4
+            t apply product H1:H4
+            t apply concat I1:I4
+            t apply median B2
+            t apply min B2
+            t apply sum I1
+            t apply stddev A1
+            "#;
+        let mut vm = VM::new(code.to_string());
+        for _ in 0..15 {
+            vm.interpret_next_line().unwrap();
+        }
+        let results: Vec<String> = (0..9)
+            .map(|col| vm.tables[0].get_cell(4, col).unwrap().to_string())
+            .collect();
+        assert_eq!(results, ["12", "4", "4", "1", "7", "4", "3", "28", "ab2cd"]);
+
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "median needs at least one number in range, found 0.");
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "min needs at least one number in range, found 0.");
+        // no numbers in range, the stored total must not be -0
+        vm.interpret_next_line().unwrap();
+        assert_eq!(vm.tables[0].get_cell(1, 8).unwrap().to_string(), "0");
+        let err = vm.interpret_next_line().unwrap_err().to_string();
+        assert_eq!(err, "stddev needs at least two numbers in range, found 1.");
+
+        // redefining a built-in replaces it
+        let mut vm = VM::new("sum define auto;each!acc!~op 1 +;".to_string());
+        vm.interpret_next_line().unwrap();
+        assert!(!vm.closures[vm.closure_idx["sum"]].is_native());
+    }
+
+    #[test]
+    pub fn operator_suggestions() {
+        use crate::autocorrect::{degree_of_closeness, keyboard_distance_matcher};
+        assert_eq!(
+            keyboard_distance_matcher("reordr_colums", Token::Operator),
+            "reorder_columns"
+        );
+        assert_eq!(
+            keyboard_distance_matcher("ad_row", Token::Operator),
+            "add_row"
+        );
+        assert_eq!(degree_of_closeness("view", "view"), 0);
+        assert_eq!(degree_of_closeness("", "view"), 32);
+        // long unrelated words used to take minutes
+        keyboard_distance_matcher("qwzvkpqwzvkpxxqwzvkpqwzvkp", Token::Operator);
+    }
+
+    #[test]
+    pub fn reversed_ranges() {
+        let code = r#"t create with headers "n"
+            t add_row "1"
+            t add_row "2"
+            t add_row "3"
+            t apply sum A3:A1
+            "#;
+        let mut vm = VM::new(code.to_string());
+        vm.interpret().unwrap();
+        let column: Vec<String> = (0..4)
+            .map(|row| vm.tables[0].get_cell(row, 0).unwrap().to_string())
+            .collect();
+        assert_eq!(column, ["1", "2", "3", "6"]);
+    }
+
+    #[test]
+    pub fn row_edits_shift_formulas() {
+        let code = r#"t create with headers "n" "x10"
+            t add_row "1" "=A1 * 10"
+            t add_row "2" "=A2 * 10"
+            t add_row "3" "=A3 * 10"
+            t add_row "total" "=A1 + A3"
+            t add_row "sum" "=SUM(A1:A3)"
+            t delete_row 1
+            t insert_row 1 "5" "=A1 * 10"
+            t update_row 2 "4" "=A2 * 10"
+            t delete_row 3
+            t delete_row 2
+            "#;
+        let mut vm = VM::new(code.to_string());
+        let csv = |vm: &VM| vm.tables[0].to_csv_with_title(false).unwrap();
+        let source = |vm: &VM, row| match vm.tables[0].get_cell(row, 1).unwrap() {
+            Cell::Formula(formula) => formula.source().to_string(),
+            cell => panic!("expected a formula, found {cell}"),
+        };
+        for _ in 0..7 {
+            vm.interpret_next_line().unwrap();
+        }
+        // the total read the deleted row, the sum only lost the first row of its range
+        assert_eq!(
+            csv(&vm),
+            "n,x10\r\n2,20\r\n3,30\r\ntotal,#REF!\r\nsum,5\r\n"
+        );
+        assert_eq!(source(&vm, 3), "SUM(A1:A2)");
+        vm.interpret_next_line().unwrap();
+        assert_eq!(
+            csv(&vm),
+            "n,x10\r\n5,50\r\n2,20\r\n3,30\r\ntotal,#REF!\r\nsum,5\r\n"
+        );
+        vm.interpret_next_line().unwrap();
+        assert_eq!(
+            csv(&vm),
+            "n,x10\r\n5,50\r\n4,40\r\n3,30\r\ntotal,#REF!\r\nsum,7\r\n"
+        );
+        assert_eq!(source(&vm, 2), "A3 * 10");
+        // deleting the last row of the range shrinks it too, deleting all of it leaves nothing
+        vm.interpret_next_line().unwrap();
+        assert_eq!(source(&vm, 3), "SUM(A2:A2)");
+        assert_eq!(
+            csv(&vm),
+            "n,x10\r\n5,50\r\n4,40\r\ntotal,#REF!\r\nsum,4\r\n"
+        );
+        vm.interpret_next_line().unwrap();
+        assert_eq!(csv(&vm), "n,x10\r\n5,50\r\ntotal,#REF!\r\nsum,#REF!\r\n");
+    }
+
+    #[test]
+    pub fn sort_and_filter_keep_formulas() {
+        let code = r#"t create with headers "n" "x10" "first" "sum"
+            t add_row "1" "=A1 * 10" "=A1" "=SUM(A1:A2)"
+            t add_row "2" "=A2 * 10" "=A1" "=SUM(A2:A3)"
+            t add_row "3" "=A3 * 10" "=A1" "=SUM(A1:A3)"
+            t sort by 1 desc
+            t filter "n" >= 2
+            u create with headers "n" "sum"
+            u add_row "5" "=SUM(A1:A2)"
+            u add_row "1"
+            u add_row "9"
+            u add_row "3"
+            u add_row "7"
+            u sort by 1
+            "#;
+        let mut vm = VM::new(code.to_string());
+        let csv = |vm: &VM, table: usize| vm.tables[table].to_csv_with_title(false).unwrap();
+        for _ in 0..5 {
+            vm.interpret_next_line().unwrap();
+        }
+        // reversing the rows keeps every range one block
+        assert_eq!(
+            csv(&vm, 0),
+            "n,x10,first,sum\r\n3,30,1,6\r\n2,20,1,5\r\n1,10,1,3\r\n"
+        );
+        vm.interpret_next_line().unwrap();
+        // the row holding 1 was filtered out from under "first" and the end of A1:A3
+        assert_eq!(
+            csv(&vm, 0),
+            "n,x10,first,sum\r\n3,30,#REF!,5\r\n2,20,#REF!,5\r\n"
+        );
+        for _ in 0..7 {
+            vm.interpret_next_line().unwrap();
+        }
+        // 5 and 1 are no longer next to each other, so A1:A2 has no rows to follow
+        assert_eq!(csv(&vm, 1), "n,sum\r\n1,\r\n3,\r\n5,#REF!\r\n7,\r\n9,\r\n");
+    }
+
+    #[test]
+    pub fn sort_total_order() {
+        let mut vm = VM::new("t create with headers \"n\"".to_string());
+        vm.interpret().unwrap();
+        let table = &mut vm.tables[0];
+        let values = ["12", "NaN", "9", "", "x", "true", "100", "-1"];
+        for (row, value) in values.iter().enumerate() {
+            table.append_row_from(vec![""]);
+            // numeric text as loaded from a file, next to real numbers
+            let cell = match row % 2 {
+                0 => Cell::String(value.to_string()),
+                _ => value.parse().unwrap(),
+            };
+            table.set_cell(row, 0, cell).unwrap();
+        }
+        for _ in 0..200 {
+            table.append_row_from(vec!["7"]);
+        }
+        let column = |table: &Table| -> Vec<String> {
+            (0..table.dimensions().0)
+                .map(|row| table.get_cell(row, 0).unwrap().to_string())
+                .filter(|value| value != "7")
+                .collect()
+        };
+        table.sort_rows(&[(0, false)]);
+        assert_eq!(
+            column(table),
+            ["-1", "9", "12", "100", "x", "true", "NaN", ""]
+        );
+        assert_eq!(table.get_cell(3, 0).unwrap().to_string(), "7");
+        table.sort_rows(&[(0, true)]);
+        assert_eq!(
+            column(table),
+            ["true", "x", "100", "12", "9", "-1", "NaN", ""]
+        );
+    }
 }
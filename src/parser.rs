@@ -0,0 +1,901 @@
+use crate::{
+    autocorrect::keyboard_distance_matcher,
+    closure::Operation,
+    compiler::{Cell, JoinKind, Modifier, Operator, Token},
+    formula::{CellRange, CellRef, Function},
+    lexer::{Lexeme, LexemeKind, Lexer, Span},
+    suggestion, warn,
+};
+use eyre::Result;
+use std::str::FromStr;
+
+/// Reported for `new_table = table ...` with an operator that doesn't build a table.
+const DERIVED_OPERATORS: &str =
+    "Only filter, group_by, pivot and join can create a new table with \"=\".";
+
+/// A name together with where it was written in the line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+/// A column given by its 1-based index or its header name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnRef {
+    Index(usize),
+    Name(String),
+}
+
+/// One column given to an axis of a `plot` statement, e.g. `x "fruit"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlotAxis {
+    pub axis: String,
+    pub column: ColumnRef,
+    pub span: Span,
+}
+
+/// Column of a `sort` statement and whether it orders from the largest value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub column: ColumnRef,
+    pub descending: bool,
+}
+
+/// Comparison of a column against a value in a `filter` statement, e.g. `"weight" > 10`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub column: ColumnRef,
+    pub(crate) operation: Operation,
+    pub value: Cell,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// Compiler intrinsic writing all tables to `table.html`.
+    Render,
+    /// Compiler intrinsic removing cached LLM responses.
+    ClearCache,
+    /// A table name without any operator after it.
+    Dangling {
+        table: Ident,
+    },
+    Create {
+        table: Ident,
+        modifiers: Vec<(Modifier, Vec<String>)>,
+    },
+    AddRow {
+        table: Ident,
+        cells: Vec<String>,
+    },
+    ExtendHeaders {
+        table: Ident,
+        headers: Vec<String>,
+    },
+    View {
+        table: Ident,
+    },
+    Apply {
+        table: Ident,
+        closure: Ident,
+        range: CellRange,
+    },
+    DefineClosure {
+        name: Ident,
+        definition: String,
+    },
+    /// Question about one or more tables, written `t1,t2 prompt "..."`.
+    Prompt {
+        tables: Vec<Ident>,
+        prompt: String,
+    },
+    Load {
+        table: Ident,
+        path: String,
+        modifiers: Vec<(Modifier, Vec<String>)>,
+    },
+    /// Stores the answer to a question about the table in one cell.
+    PromptInto {
+        table: Ident,
+        cell: CellRef,
+        prompt: String,
+    },
+    /// Asks the question once per row, storing the answers in `column`.
+    FillColumn {
+        table: Ident,
+        column: String,
+        prompt: String,
+    },
+    Set {
+        table: Ident,
+        cell: CellRef,
+        value: String,
+    },
+    DeleteRow {
+        table: Ident,
+        row: usize,
+    },
+    /// Inserts a row before `row`, `row` may be the row count to append.
+    InsertRow {
+        table: Ident,
+        row: usize,
+        cells: Vec<String>,
+    },
+    UpdateRow {
+        table: Ident,
+        row: usize,
+        cells: Vec<String>,
+    },
+    /// Appends a column, `value` fills every row and may be a formula filled down.
+    AddColumn {
+        table: Ident,
+        header: String,
+        value: Option<String>,
+    },
+    DropColumn {
+        table: Ident,
+        column: ColumnRef,
+    },
+    RenameColumn {
+        table: Ident,
+        column: ColumnRef,
+        header: String,
+    },
+    /// Moves a column to the 1-based position `to`.
+    MoveColumn {
+        table: Ident,
+        column: ColumnRef,
+        to: usize,
+    },
+    ReorderColumns {
+        table: Ident,
+        columns: Vec<ColumnRef>,
+    },
+    /// Stable sort on one or more columns, empty cells always last.
+    Sort {
+        table: Ident,
+        keys: Vec<SortKey>,
+    },
+    /// Keeps the rows matching every condition, in a new table `into` when given as
+    /// `into = table filter ...`.
+    Filter {
+        table: Ident,
+        conditions: Vec<Condition>,
+        into: Option<Ident>,
+    },
+    /// One row per distinct value of `columns` in the new table `into`, with a column per
+    /// aggregate.
+    GroupBy {
+        table: Ident,
+        columns: Vec<ColumnRef>,
+        aggregates: Vec<(Function, ColumnRef)>,
+        into: Ident,
+    },
+    /// Values of `rows` down the side and of `columns` across the top of the new table `into`,
+    /// each cell aggregating the matching rows.
+    Pivot {
+        table: Ident,
+        rows: ColumnRef,
+        columns: ColumnRef,
+        aggregate: (Function, ColumnRef),
+        into: Ident,
+    },
+    /// Combines the rows of `table` and `other` whose keys match into the new table `into`.
+    Join {
+        table: Ident,
+        other: Ident,
+        kind: JoinKind,
+        keys: (ColumnRef, ColumnRef),
+        into: Ident,
+    },
+    /// Adds the rows of `other` below those of `table`, matching columns by header.
+    Append {
+        table: Ident,
+        other: Ident,
+    },
+    Plot {
+        table: Ident,
+        kind: Ident,
+        axes: Vec<PlotAxis>,
+        output: Option<String>,
+    },
+}
+
+pub struct Parser<'a> {
+    line: &'a str,
+    lexer: Lexer<'a>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(line: &'a str) -> Parser<'a> {
+        Parser {
+            line,
+            lexer: Lexer::new(line),
+        }
+    }
+
+    fn ident(&self, lexeme: Lexeme) -> Result<Ident> {
+        match lexeme.kind {
+            LexemeKind::Ident(name) => Ok(Ident {
+                name,
+                span: lexeme.span,
+            }),
+            LexemeKind::Number(_) => Ok(Ident {
+                name: self.line[lexeme.span.start..lexeme.span.end].to_string(),
+                span: lexeme.span,
+            }),
+            LexemeKind::Str(_) => Err(lexeme
+                .span
+                .report(self.line, "Expected a name but found a string literal.")),
+        }
+    }
+
+    fn expect(&mut self, what: &str) -> Result<Lexeme> {
+        self.lexer.next().ok_or_else(|| {
+            Span::point(self.line.len() + 1)
+                .report(self.line, format!("No token found for {what}."))
+        })
+    }
+
+    fn string_literal(&self, lexeme: Lexeme) -> Result<String> {
+        match lexeme.kind {
+            LexemeKind::Str(s) => Ok(s),
+            _ => {
+                suggestion!("String literals must be enclosed in double quotes (\").");
+                Err(lexeme.span.report(
+                    self.line,
+                    format!(
+                        "String literal not found, found \"{}\" instead.",
+                        &self.line[lexeme.span.start..lexeme.span.end]
+                    ),
+                ))
+            }
+        }
+    }
+
+    fn column_ref(&self, lexeme: &Lexeme) -> Result<ColumnRef> {
+        match &lexeme.kind {
+            LexemeKind::Str(name) => Ok(ColumnRef::Name(name.clone())),
+            _ => match lexeme.as_usize() {
+                Some(index) if index > 0 => Ok(ColumnRef::Index(index)),
+                _ => Err(lexeme.span.report(
+                    self.line,
+                    "Expected a header name or a 1-based column number.",
+                )),
+            },
+        }
+    }
+
+    /// Reads the next lexeme as a column given by header name or 1-based number.
+    fn column(&mut self) -> Result<ColumnRef> {
+        let lexeme = self.expect("column")?;
+        self.column_ref(&lexeme)
+    }
+
+    /// Fails on any lexeme left on the line.
+    fn end(&mut self, message: &str) -> Result<()> {
+        match self.lexer.next() {
+            Some(extra) => Err(extra.span.report(self.line, message)),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads `column op value`, the value is a number or a string literal.
+    fn condition(&mut self) -> Result<Condition> {
+        let column = self.column()?;
+        let operation = self.expect("comparison")?;
+        let operation = match &self.line[operation.span.start..operation.span.end] {
+            "=" => Operation::Equal,
+            word => match Operation::from_str(word) {
+                Ok(
+                    op @ (Operation::Less
+                    | Operation::Greater
+                    | Operation::Equal
+                    | Operation::NotEqual
+                    | Operation::LessEqual
+                    | Operation::GreaterEqual),
+                ) => op,
+                _ => {
+                    return Err(operation.span.report(
+                        self.line,
+                        "Expected a comparison, one of = == != <> < > <= >=.",
+                    ))
+                }
+            },
+        };
+        let value = self.expect("value to compare with")?;
+        let value = match value.kind {
+            LexemeKind::Number(n) => Cell::Number(n),
+            _ => Cell::from_str(&self.string_literal(value)?)?,
+        };
+        Ok(Condition {
+            column,
+            operation,
+            value,
+        })
+    }
+
+    /// Parses `source operator ...` after `table =`, for the operators building a new table.
+    fn derived_table(&mut self, into: Ident) -> Result<Statement> {
+        let source = self.lexer.next().unwrap();
+        let source = self.ident(source)?;
+        self.statement(source, Some(into))
+    }
+
+    /// Table named by `into = table ...`, which the operators building a new table need.
+    fn target(&self, table: &Ident, into: Option<Ident>) -> Result<Ident> {
+        into.ok_or_else(|| {
+            table.span.report(
+                self.line,
+                format!(
+                    "This creates a new table, name it as in \"new_table = {} ...\".",
+                    table.name
+                ),
+            )
+        })
+    }
+
+    /// Reads an aggregate such as `sum "amount"`.
+    fn aggregate(&mut self) -> Result<(Function, ColumnRef)> {
+        let function = self.expect("aggregate function")?;
+        let word = &self.line[function.span.start..function.span.end];
+        let function = match (&function.kind, Function::from_str(word)) {
+            (LexemeKind::Ident(_), Ok(function)) => function,
+            _ => {
+                return Err(function.span.report(
+                    self.line,
+                    "Expected an aggregate, one of sum, avg, min, max or count.",
+                ))
+            }
+        };
+        Ok((function, self.column()?))
+    }
+
+    /// Reads every remaining lexeme as a string literal.
+    fn string_literals(&mut self) -> Result<Vec<String>> {
+        let mut literals = Vec::new();
+        while let Some(lexeme) = self.lexer.next() {
+            literals.push(self.string_literal(lexeme)?);
+        }
+        Ok(literals)
+    }
+
+    /// Consumes the bare word `word`.
+    fn keyword(&mut self, word: &str) -> Result<()> {
+        let lexeme = self.expect(word)?;
+        if &self.line[lexeme.span.start..lexeme.span.end] != word {
+            return Err(lexeme
+                .span
+                .report(self.line, format!("Expected \"{word}\" here.")));
+        }
+        Ok(())
+    }
+
+    fn coordinate(&mut self) -> Result<Option<(usize, usize)>> {
+        if self.lexer.peek().is_none() {
+            return Ok(None);
+        }
+        let mut index = |what: &str| -> Result<usize> {
+            let lexeme = self.expect(what)?;
+            lexeme.as_usize().ok_or_else(|| {
+                lexeme.span.report(
+                    self.line,
+                    format!("Expected a non-negative integer for {what}."),
+                )
+            })
+        };
+        Ok(Some((index("row-index")?, index("column-index")?)))
+    }
+
+    /// Splits a comma separated list of table names, keeping the span of each.
+    fn table_list(tables: Ident) -> Vec<Ident> {
+        let mut start = tables.span.start;
+        tables
+            .name
+            .split(',')
+            .map(|name| {
+                let span = Span::new(start, start + name.len());
+                start = span.end + 1;
+                Ident {
+                    name: name.to_string(),
+                    span,
+                }
+            })
+            .filter(|table| !table.name.is_empty())
+            .collect()
+    }
+
+    /// Reads the cells of an `apply`: `row col [row col]`, `B2`, `A1:C10`, `B:B` or a header
+    /// name in double quotes.
+    fn cell_range(&mut self) -> Result<CellRange> {
+        let lexeme = self.lexer.peek().ok_or_else(|| {
+            Span::point(self.line.len() + 1).report(self.line, "No token found for cell range.")
+        })?;
+        match lexeme.kind {
+            LexemeKind::Number(_) => {
+                let start = self.coordinate()?.unwrap();
+                let end = match self.lexer.peek() {
+                    Some(Lexeme {
+                        kind: LexemeKind::Number(_),
+                        ..
+                    }) => self.coordinate()?.unwrap(),
+                    _ => start,
+                };
+                Ok(CellRange::Cells(start.into(), end.into()))
+            }
+            LexemeKind::Str(name) => {
+                self.lexer.next();
+                Ok(CellRange::Header(name))
+            }
+            LexemeKind::Ident(word) => {
+                self.lexer.next();
+                CellRange::parse(&word).ok_or_else(|| {
+                    suggestion!("Cells can be given as \x1b[96mrow col\x1b[0m, \x1b[96mB2\x1b[0m, \x1b[96mA1:C10\x1b[0m, \x1b[96mB:B\x1b[0m or a header name in double quotes.");
+                    lexeme
+                        .span
+                        .report(self.line, format!("\"{word}\" is not a cell range."))
+                })
+            }
+        }
+    }
+
+    /// Reads a 1-based row number, as in A1 notation, as a 0-based index.
+    fn row_index(&mut self) -> Result<usize> {
+        let lexeme = self.expect("row number")?;
+        match lexeme.as_usize() {
+            Some(number) if number > 0 => Ok(number - 1),
+            _ => Err(lexeme
+                .span
+                .report(self.line, "Expected a 1-based row number.")),
+        }
+    }
+
+    /// Reads a single cell written in A1 notation such as `B2`.
+    fn cell(&mut self) -> Result<CellRef> {
+        let span = self
+            .lexer
+            .peek()
+            .map(|lexeme| lexeme.span)
+            .unwrap_or_else(|| Span::point(self.line.len() + 1));
+        if let Some(Lexeme {
+            kind: LexemeKind::Ident(word),
+            ..
+        }) = self.lexer.peek()
+        {
+            if let Some(CellRange::Cells(from, to)) = CellRange::parse(&word) {
+                if from == to {
+                    self.lexer.next();
+                    return Ok(from);
+                }
+            }
+        }
+        suggestion!("A single cell is written in A1 notation, \x1b[96mB3\x1b[0m is row 3 of the second column.");
+        Err(span.report(self.line, "Expected a single cell such as B2."))
+    }
+
+    /// Reads `axis column...` groups up to an optional `to "path"`.
+    fn plot_axes(&mut self) -> Result<(Vec<PlotAxis>, Option<String>)> {
+        let mut axes = Vec::new();
+        while let Some(lexeme) = self.lexer.next() {
+            let axis = self.ident(lexeme.clone())?;
+            if axis.name == "to" {
+                let path = self.expect("plot output path")?;
+                let path = self.string_literal(path)?;
+                if let Some(extra) = self.lexer.next() {
+                    return Err(extra
+                        .span
+                        .report(self.line, "Unexpected token after plot output path."));
+                }
+                return Ok((axes, Some(path)));
+            }
+            let mut value = self.expect(&format!("column of axis {}", axis.name))?;
+            let mut span_start = axis.span.start;
+            loop {
+                let column = self.column_ref(&value)?;
+                axes.push(PlotAxis {
+                    axis: axis.name.clone(),
+                    column,
+                    span: Span::new(span_start, value.span.end),
+                });
+                // further columns for the same axis, e.g. `y "sales" "costs"`
+                match self.lexer.peek() {
+                    Some(Lexeme {
+                        kind: LexemeKind::Str(_) | LexemeKind::Number(_),
+                        ..
+                    }) => {
+                        value = self.lexer.next().unwrap();
+                        span_start = value.span.start;
+                    }
+                    _ => break,
+                }
+            }
+        }
+        Ok((axes, None))
+    }
+
+    /// Reads `with`/`and` modifiers, only those listed in `flags` may appear without data.
+    fn modifiers(&mut self, flags: &[Modifier]) -> Result<Vec<(Modifier, Vec<String>)>> {
+        let mut modifiers = Vec::new();
+        while let Some(lexeme) = self.lexer.next() {
+            let word = &self.line[lexeme.span.start..lexeme.span.end];
+            if !Modifier::is_modifier(word) {
+                let correction = keyboard_distance_matcher(word, Token::Modifier);
+                suggestion!("You may want to use \x1b[96m{correction}\x1b[0m instead.");
+                warn!(
+                    "Expected modifier but found \"{word}\", ignoring token\n{}",
+                    lexeme.span.underline(self.line)
+                );
+                continue;
+            }
+            let modifier_lexeme = self.lexer.next().ok_or_else(|| {
+                lexeme
+                    .span
+                    .report(self.line, format!("No token found for modifier {word}."))
+            })?;
+            let modifier_word = &self.line[modifier_lexeme.span.start..modifier_lexeme.span.end];
+            let modifier = Modifier::from_str(modifier_word)
+                .map_err(|e| modifier_lexeme.span.report(self.line, e))?;
+
+            let mut cell_data = Vec::new();
+            while let Some(Lexeme {
+                kind: LexemeKind::Str(_),
+                ..
+            }) = self.lexer.peek()
+            {
+                let literal = self.lexer.next().unwrap();
+                cell_data.push(self.string_literal(literal)?);
+            }
+            if cell_data.is_empty() && !flags.contains(&modifier) {
+                return Err(modifier_lexeme.span.report(
+                    self.line,
+                    format!("No data found for modifier {modifier_word}"),
+                ));
+            }
+            modifiers.push((modifier, cell_data));
+        }
+        Ok(modifiers)
+    }
+
+    pub fn parse(mut self) -> Result<Statement> {
+        let table = self.lexer.next().ok_or_else(|| {
+            Span::point(0).report(self.line, "No token found referencing to table")
+        })?;
+        let table = self.ident(table)?;
+        if table.name == "render" {
+            return Ok(Statement::Render);
+        }
+        if table.name == "clear_cache" {
+            return Ok(Statement::ClearCache);
+        }
+
+        self.statement(table, None)
+    }
+
+    /// Parses the part of a statement after the table name, `into` is the new table named by
+    /// `into = table ...`.
+    fn statement(&mut self, table: Ident, into: Option<Ident>) -> Result<Statement> {
+        let span = self.lexer.peek().map_or(table.span, |lexeme| lexeme.span);
+        let Some(operator) = self.lexer.next() else {
+            if into.is_some() {
+                return Err(span.report(self.line, DERIVED_OPERATORS));
+            }
+            return Ok(Statement::Dangling { table });
+        };
+        let operator_word = &self.line[operator.span.start..operator.span.end];
+        let operator =
+            Operator::from_str(operator_word).map_err(|e| operator.span.report(self.line, e))?;
+        if into.is_some()
+            && !matches!(
+                operator,
+                Operator::Filter
+                    | Operator::GroupBy
+                    | Operator::Pivot
+                    | Operator::Join
+                    | Operator::LeftJoin
+                    | Operator::OuterJoin
+            )
+        {
+            return Err(span.report(self.line, DERIVED_OPERATORS));
+        }
+        if table.name.contains(',') && !matches!(operator, Operator::Prompt) {
+            return Err(table.span.report(
+                self.line,
+                format!("Only prompt can reference several tables, {operator} takes one."),
+            ));
+        }
+
+        Ok(match operator {
+            Operator::Assignment => match self.lexer.peek() {
+                Some(Lexeme {
+                    kind: LexemeKind::Ident(word),
+                    ..
+                }) if !Modifier::is_modifier(&word) => self.derived_table(table)?,
+                _ => Statement::Create {
+                    table,
+                    modifiers: self.modifiers(&[])?,
+                },
+            },
+            Operator::AddRow => Statement::AddRow {
+                table,
+                cells: self.string_literals()?,
+            },
+            Operator::ExtendHeaders => Statement::ExtendHeaders {
+                table,
+                headers: self.string_literals()?,
+            },
+            Operator::View => Statement::View { table },
+            Operator::Apply => {
+                let closure = self.expect("closure name")?;
+                let closure = self.ident(closure)?;
+                let range = match self.lexer.peek() {
+                    Some(Lexeme {
+                        kind: LexemeKind::Number(_),
+                        ..
+                    }) => {
+                        let start = self.coordinate()?.unwrap();
+                        let end = self.coordinate()?.unwrap_or_else(|| {
+                            warn!("Defaulting end to start cell address.");
+                            start
+                        });
+                        CellRange::Cells(start.into(), end.into())
+                    }
+                    _ => self.cell_range()?,
+                };
+                Statement::Apply {
+                    table,
+                    closure,
+                    range,
+                }
+            }
+            Operator::DefineClosure => Statement::DefineClosure {
+                name: table,
+                definition: self
+                    .lexer
+                    .rest()
+                    .map(|(text, _)| text.to_string())
+                    .unwrap_or_default(),
+            },
+            Operator::Load => {
+                let path = self.expect("file path")?;
+                Statement::Load {
+                    table,
+                    path: self.string_literal(path)?,
+                    modifiers: self.modifiers(&[Modifier::WithHeader])?,
+                }
+            }
+            Operator::PromptInto => {
+                let cell = self.cell()?;
+                let prompt = self.expect("prompt")?;
+                Statement::PromptInto {
+                    table,
+                    cell,
+                    prompt: self.string_literal(prompt)?,
+                }
+            }
+            Operator::FillColumn => {
+                let column = self.expect("column name")?;
+                let column = self.string_literal(column)?;
+                self.keyword("from")?;
+                self.keyword("prompt")?;
+                let prompt = self.expect("prompt")?;
+                Statement::FillColumn {
+                    table,
+                    column,
+                    prompt: self.string_literal(prompt)?,
+                }
+            }
+            Operator::Set => {
+                let cell = self.cell()?;
+                let value = self.expect("cell value")?;
+                let value = self.string_literal(value)?;
+                if let Some(extra) = self.lexer.next() {
+                    return Err(extra.span.report(self.line, "set takes a single value."));
+                }
+                Statement::Set { table, cell, value }
+            }
+            Operator::DeleteRow => Statement::DeleteRow {
+                table,
+                row: self.row_index()?,
+            },
+            Operator::InsertRow => Statement::InsertRow {
+                table,
+                row: self.row_index()?,
+                cells: self.string_literals()?,
+            },
+            Operator::UpdateRow => Statement::UpdateRow {
+                table,
+                row: self.row_index()?,
+                cells: self.string_literals()?,
+            },
+            Operator::AddColumn => {
+                let header = self.expect("column header")?;
+                let header = self.string_literal(header)?;
+                let value = match self.lexer.next() {
+                    Some(value) => Some(self.string_literal(value)?),
+                    None => None,
+                };
+                self.end("add_column takes a header and an optional value.")?;
+                Statement::AddColumn {
+                    table,
+                    header,
+                    value,
+                }
+            }
+            Operator::DropColumn => {
+                let column = self.column()?;
+                self.end("drop_column takes a single column.")?;
+                Statement::DropColumn { table, column }
+            }
+            Operator::RenameColumn => {
+                let column = self.column()?;
+                let header = self.expect("new column header")?;
+                let header = self.string_literal(header)?;
+                self.end("rename_column takes a column and its new header.")?;
+                Statement::RenameColumn {
+                    table,
+                    column,
+                    header,
+                }
+            }
+            Operator::MoveColumn => {
+                let column = self.column()?;
+                let lexeme = self.expect("column position")?;
+                let to = match lexeme.as_usize() {
+                    Some(to) if to > 0 => to,
+                    _ => {
+                        return Err(lexeme
+                            .span
+                            .report(self.line, "Expected a 1-based column position."))
+                    }
+                };
+                self.end("move_column takes a column and its new position.")?;
+                Statement::MoveColumn { table, column, to }
+            }
+            Operator::ReorderColumns => {
+                let mut columns = vec![self.column()?];
+                while let Some(lexeme) = self.lexer.next() {
+                    columns.push(self.column_ref(&lexeme)?);
+                }
+                Statement::ReorderColumns { table, columns }
+            }
+            Operator::Sort => {
+                self.keyword("by")?;
+                let mut keys: Vec<SortKey> = vec![];
+                while let Some(lexeme) = self.lexer.next() {
+                    match &lexeme.kind {
+                        LexemeKind::Ident(word) if matches!(word.as_str(), "asc" | "desc") => {
+                            let Some(key) = keys.last_mut() else {
+                                return Err(lexeme
+                                    .span
+                                    .report(self.line, "Expected a column before the order."));
+                            };
+                            key.descending = word == "desc";
+                        }
+                        _ => keys.push(SortKey {
+                            column: self.column_ref(&lexeme)?,
+                            descending: false,
+                        }),
+                    }
+                }
+                if keys.is_empty() {
+                    return Err(Span::point(self.line.len() + 1)
+                        .report(self.line, "Expected a column to sort by."));
+                }
+                Statement::Sort { table, keys }
+            }
+            Operator::Filter => {
+                let mut conditions = vec![self.condition()?];
+                while self.lexer.peek().is_some() {
+                    self.keyword("and")?;
+                    conditions.push(self.condition()?);
+                }
+                Statement::Filter {
+                    table,
+                    conditions,
+                    into,
+                }
+            }
+            Operator::GroupBy => {
+                let into = self.target(&table, into)?;
+                let mut columns = vec![self.column()?];
+                loop {
+                    let lexeme = self.expect("\"aggregate\"")?;
+                    match &lexeme.kind {
+                        LexemeKind::Ident(word) if word == "aggregate" => break,
+                        _ => columns.push(self.column_ref(&lexeme)?),
+                    }
+                }
+                let mut aggregates = vec![self.aggregate()?];
+                while self.lexer.peek().is_some() {
+                    aggregates.push(self.aggregate()?);
+                }
+                Statement::GroupBy {
+                    table,
+                    columns,
+                    aggregates,
+                    into,
+                }
+            }
+            Operator::Pivot => {
+                let into = self.target(&table, into)?;
+                let rows = self.column()?;
+                self.keyword("by")?;
+                let columns = self.column()?;
+                self.keyword("aggregate")?;
+                let aggregate = self.aggregate()?;
+                self.end("pivot takes a single aggregate.")?;
+                Statement::Pivot {
+                    table,
+                    rows,
+                    columns,
+                    aggregate,
+                    into,
+                }
+            }
+            Operator::Join | Operator::LeftJoin | Operator::OuterJoin => {
+                let into = self.target(&table, into)?;
+                let other = self.expect("table to join with")?;
+                let other = self.ident(other)?;
+                self.keyword("on")?;
+                let key = self.column()?;
+                let keys = match self.lexer.peek() {
+                    Some(lexeme) if &self.line[lexeme.span.start..lexeme.span.end] == "=" => {
+                        self.lexer.next();
+                        (key, self.column()?)
+                    }
+                    _ => (key.clone(), key),
+                };
+                self.end("Expected the join to end after its key columns.")?;
+                Statement::Join {
+                    table,
+                    other,
+                    kind: match operator {
+                        Operator::LeftJoin => JoinKind::Left,
+                        Operator::OuterJoin => JoinKind::Outer,
+                        _ => JoinKind::Inner,
+                    },
+                    keys,
+                    into,
+                }
+            }
+            Operator::Append => {
+                let other = self.expect("table to append")?;
+                let other = self.ident(other)?;
+                self.end("append takes a single table.")?;
+                Statement::Append { table, other }
+            }
+            Operator::Plot => {
+                let kind = self.expect("plot kind")?;
+                let kind = self.ident(kind)?;
+                let (axes, output) = self.plot_axes()?;
+                Statement::Plot {
+                    table,
+                    kind,
+                    axes,
+                    output,
+                }
+            }
+            Operator::Prompt => {
+                let mut literal = self.lexer.clone();
+                let prompt = match (literal.next(), literal.next()) {
+                    (
+                        Some(Lexeme {
+                            kind: LexemeKind::Str(s),
+                            ..
+                        }),
+                        None,
+                    ) => s,
+                    _ => self
+                        .lexer
+                        .rest()
+                        .map(|(text, _)| text.trim_matches('"').to_string())
+                        .unwrap_or_default(),
+                };
+                Statement::Prompt {
+                    tables: Self::table_list(table),
+                    prompt,
+                }
+            }
+        })
+    }
+}
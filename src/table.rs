@@ -9,7 +9,13 @@ use std::{
     str::FromStr,
 };
 
-use crate::compiler::ResponsePlotType;
+use crate::{
+    closure::Operation,
+    compiler::ResponsePlotType,
+    formula::{column_name, evaluation_order, CellRange, CellRef, Formula, Function},
+    llm::LlmBackend,
+    parser::ColumnRef,
+};
 
 #[macro_export]
 macro_rules! warn {
@@ -28,7 +34,11 @@ macro_rules! suggestion {
 }
 
 pub trait Save {
-    fn to_csv(&self) -> Result<String>;
+    /// CSV output as described in RFC 4180, `title` controls the leading title record.
+    fn to_csv_with_title(&self, title: bool) -> Result<String>;
+    fn to_csv(&self) -> Result<String> {
+        self.to_csv_with_title(true)
+    }
     fn to_html(&self) -> Result<String>;
     fn save_to_string(&self, output_type: OutputType) -> Result<String> {
         match output_type {
@@ -52,6 +62,17 @@ pub struct Table {
     headers: Row,
     pub(crate) rows: Vec<Row>,
 }
+/// Which rows a join keeps besides the matching ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    /// Only rows with a match in both tables.
+    Inner,
+    /// Every row of the left table, with empty cells where the right has no match.
+    Left,
+    /// Every row of both tables.
+    Outer,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Row {
     pub(crate) cells: Vec<Cell>,
@@ -59,12 +80,15 @@ pub struct Row {
 impl Row {
     pub fn new(row_size: usize) -> Row {
         Row {
-            cells: Vec::from_iter(std::iter::repeat(Cell::Empty).take(row_size)),
+            cells: vec![Cell::Empty; row_size],
         }
     }
     pub fn len(&self) -> usize {
         self.cells.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
 }
 #[derive(Default, Clone, Debug, PartialEq, serde::Deserialize)]
 pub enum Cell {
@@ -73,6 +97,9 @@ pub enum Cell {
     Boolean(bool),
     #[default]
     Empty,
+    /// Expression such as `=SUM(A1:A3)`, acting as its last computed value.
+    #[serde(skip)]
+    Formula(Box<Formula>),
 }
 
 impl Serialize for Cell {
@@ -85,6 +112,7 @@ impl Serialize for Cell {
             Cell::Number(n) => serializer.serialize_f64(*n),
             Cell::Boolean(b) => serializer.serialize_bool(*b),
             Cell::Empty => serializer.serialize_none(),
+            Cell::Formula(formula) => formula.value().serialize(serializer),
         }
     }
 }
@@ -94,6 +122,7 @@ impl Add for Cell {
 
     fn add(self, rhs: Self) -> Self::Output {
         match self {
+            Cell::Formula(formula) => formula.value().clone() + rhs,
             Cell::String(s) => Cell::String(format!("{}{}", s, rhs)),
             Cell::Number(n) => match rhs {
                 Cell::String(s) => {
@@ -106,6 +135,7 @@ impl Add for Cell {
                 Cell::Number(n2) => Cell::Number(n + n2),
                 Cell::Boolean(b) => Cell::Number(n + b as u8 as f64),
                 Cell::Empty => Cell::Number(n),
+                Cell::Formula(formula) => Cell::Number(n) + formula.value().clone(),
             },
             Cell::Boolean(b) => match rhs {
                 Cell::String(s) => {
@@ -118,9 +148,11 @@ impl Add for Cell {
                 Cell::Number(n) => Cell::Number(b as u8 as f64 + n),
                 Cell::Boolean(b2) => Cell::Boolean(b || b2),
                 Cell::Empty => Cell::Boolean(b),
+                Cell::Formula(formula) => Cell::Boolean(b) + formula.value().clone(),
             },
             Cell::Empty => match rhs {
                 Cell::Empty => Cell::Empty,
+                Cell::Formula(formula) => formula.value().clone(),
                 _ => rhs,
             },
         }
@@ -128,10 +160,12 @@ impl Add for Cell {
 }
 impl AddAssign for Cell {
     fn add_assign(&mut self, rhs: Self) {
+        let rhs = rhs.into_value();
         match self {
+            Cell::Formula(formula) => *self = formula.value().clone() + rhs,
             Cell::String(s) => s.push_str(&rhs.to_string()),
             Cell::Number(n) => match rhs {
-                Cell::Empty => {}
+                Cell::Empty | Cell::Formula(_) => {}
                 Cell::Number(n2) => *n += n2,
                 Cell::Boolean(b) => *n += b as u8 as f64,
                 Cell::String(s) => {
@@ -152,7 +186,7 @@ impl AddAssign for Cell {
                 }
                 Cell::Number(n) => *self = Cell::Number(*b as u8 as f64 + n),
                 Cell::Boolean(b2) => *b = *b || b2,
-                Cell::Empty => {}
+                Cell::Empty | Cell::Formula(_) => {}
             },
             Cell::Empty => match rhs {
                 Cell::Empty => {}
@@ -166,7 +200,9 @@ impl std::ops::Sub for Cell {
     type Output = Cell;
 
     fn sub(self, rhs: Self) -> Self::Output {
+        let rhs = rhs.into_value();
         match self {
+            Cell::Formula(formula) => formula.value().clone() - rhs,
             Cell::Number(n1) => match rhs {
                 Cell::String(s) => {
                     if let Ok(n2) = s.parse::<f64>() {
@@ -177,7 +213,7 @@ impl std::ops::Sub for Cell {
                 }
                 Cell::Number(n2) => Cell::Number(n1 - n2),
                 Cell::Boolean(b) => Cell::Number(n1 - b as u8 as f64),
-                Cell::Empty => self,
+                Cell::Empty | Cell::Formula(_) => self,
             },
             Cell::Boolean(b) => match rhs {
                 Cell::String(s) => {
@@ -189,7 +225,7 @@ impl std::ops::Sub for Cell {
                 }
                 Cell::Number(n) => Cell::Number(b as u8 as f64 - n),
                 Cell::Boolean(b2) => Cell::Boolean(b ^ b2),
-                Cell::Empty => self,
+                Cell::Empty | Cell::Formula(_) => self,
             },
             Cell::String(s) => match rhs {
                 Cell::String(s2) => Cell::String(s.replace(&s2, "")),
@@ -207,10 +243,10 @@ impl std::ops::Sub for Cell {
                         Cell::String(s) - Cell::String(b.to_string())
                     }
                 }
-                Cell::Empty => Cell::String(s),
+                Cell::Empty | Cell::Formula(_) => Cell::String(s),
             },
             Cell::Empty => match rhs {
-                Cell::Empty => Cell::Empty,
+                Cell::Empty | Cell::Formula(_) => Cell::Empty,
                 Cell::Number(n) => Cell::Number(-n),
                 Cell::String(_) => Cell::Empty,
                 Cell::Boolean(b) => Cell::Boolean(!b),
@@ -223,6 +259,9 @@ impl std::ops::Mul for Cell {
     type Output = Result<Cell>;
 
     fn mul(self, rhs: Self) -> Self::Output {
+        if matches!(self, Cell::Formula(_)) || matches!(rhs, Cell::Formula(_)) {
+            return self.into_value() * rhs.into_value();
+        }
         let lhs = match self {
             Cell::Number(n) => n,
             Cell::Boolean(b) => b as u8 as f64,
@@ -243,6 +282,9 @@ impl std::ops::Div for Cell {
     type Output = Result<Cell>;
 
     fn div(self, rhs: Self) -> Self::Output {
+        if matches!(self, Cell::Formula(_)) || matches!(rhs, Cell::Formula(_)) {
+            return self.into_value() / rhs.into_value();
+        }
         let lhs = match self {
             Cell::Number(n) => n,
             Cell::Boolean(b) => b as u8 as f64,
@@ -250,9 +292,9 @@ impl std::ops::Div for Cell {
             _ => bail!("Cell of type a string cannot be divided."),
         };
         let rhs = match rhs {
-            Cell::Number(n) if n == 0_f64 => bail!("Cannot divide by zero!"),
+            Cell::Number(0_f64) => bail!("Cannot divide by zero!"),
             Cell::Boolean(false) => bail!("Cannot divide by zero!"),
-            Cell::Number(n) if n != 0_f64 => n,
+            Cell::Number(n) => n,
             Cell::Boolean(true) => 1_f64,
             Cell::Empty => return Ok(self),
             _ => bail!("Cell cannot be divided by a string."),
@@ -266,15 +308,18 @@ impl std::ops::Rem for Cell {
     type Output = Result<Cell>;
 
     fn rem(self, rhs: Self) -> Self::Output {
+        if matches!(self, Cell::Formula(_)) || matches!(rhs, Cell::Formula(_)) {
+            return self.into_value() % rhs.into_value();
+        }
         let lhs = match self {
             Cell::Number(n) => n,
             Cell::Boolean(b) => b as u8 as f64,
             _ => bail!("Cell of type a string or empty cell cannot be divided."),
         };
         let rhs = match rhs {
-            Cell::Number(n) if n == 0_f64 => bail!("Cannot divide by zero!"),
+            Cell::Number(0_f64) => bail!("Cannot divide by zero!"),
             Cell::Boolean(false) => bail!("Cannot divide by zero!"),
-            Cell::Number(n) if n != 0_f64 => n,
+            Cell::Number(n) => n,
             Cell::Boolean(true) => 1_f64,
             _ => bail!("Cell cannot be divided by a string or empty cell."),
         };
@@ -283,11 +328,114 @@ impl std::ops::Rem for Cell {
     }
 }
 
+macro_rules! impl_bitwise {
+    ($trait:ident, $method:ident, $op:tt) => {
+        impl std::ops::$trait for Cell {
+            type Output = Result<Cell>;
+
+            fn $method(self, rhs: Self) -> Self::Output {
+                Ok(match (self.into_value(), rhs.into_value()) {
+                    // an empty cell only stands in for a missing number or boolean
+                    (Cell::Empty, Cell::String(s)) | (Cell::String(s), Cell::Empty) => {
+                        bail!("Bitwise operations can't be applied on string \"{s}\".")
+                    }
+                    (Cell::Empty, other) | (other, Cell::Empty) => other,
+                    (Cell::Boolean(a), Cell::Boolean(b)) => Cell::Boolean(a $op b),
+                    (lhs, rhs) => Cell::Number((lhs.as_integer()? $op rhs.as_integer()?) as f64),
+                })
+            }
+        }
+    };
+}
+impl_bitwise!(BitAnd, bitand, &);
+impl_bitwise!(BitOr, bitor, |);
+impl_bitwise!(BitXor, bitxor, ^);
+
+impl Cell {
+    /// Truthiness used by conditionals, empty cells, `false`, `0` and `""` are false.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Cell::Boolean(b) => *b,
+            Cell::Number(n) => *n != 0_f64,
+            Cell::String(s) => !s.is_empty(),
+            Cell::Empty => false,
+            Cell::Formula(formula) => formula.value().is_truthy(),
+        }
+    }
+    /// The computed value of a formula, any other cell as is.
+    pub fn value(&self) -> &Cell {
+        match self {
+            Cell::Formula(formula) => formula.value(),
+            cell => cell,
+        }
+    }
+    pub fn into_value(self) -> Cell {
+        match self {
+            Cell::Formula(formula) => formula.value().clone(),
+            cell => cell,
+        }
+    }
+    /// Integral value of a number or boolean cell, used by bitwise operations.
+    fn as_integer(&self) -> Result<i64> {
+        match self {
+            Cell::Number(n) if n.fract() == 0_f64 && n.abs() <= (1_u64 << 53) as f64 => {
+                Ok(*n as i64)
+            }
+            Cell::Number(n) => bail!("Bitwise operations need integral numbers, found {n}."),
+            Cell::Boolean(b) => Ok(*b as i64),
+            Cell::String(s) => bail!("Bitwise operations can't be applied on string \"{s}\"."),
+            Cell::Empty => bail!("Bitwise operations can't be applied on empty cells."),
+            Cell::Formula(formula) => formula.value().as_integer(),
+        }
+    }
+    /// Number held by the cell, numeric text included.
+    pub(crate) fn numeric(&self) -> Option<f64> {
+        match self.value() {
+            Cell::Number(n) => Some(*n),
+            Cell::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+    /// Compares two cells the way a spreadsheet does, an empty cell acts as `0`, `""` or
+    /// `false` depending on the other side. Strings holding numbers compare as numbers.
+    pub fn compare(&self, other: &Cell) -> Result<std::cmp::Ordering> {
+        use std::cmp::Ordering;
+        let as_number = |cell: &Cell| match cell {
+            Cell::Number(n) => Some(*n),
+            Cell::Boolean(b) => Some(*b as u8 as f64),
+            Cell::String(s) => s.parse::<f64>().ok(),
+            Cell::Empty => Some(0_f64),
+            Cell::Formula(_) => None,
+        };
+        match (self.value(), other.value()) {
+            (Cell::Empty, Cell::Empty) => Ok(Ordering::Equal),
+            (Cell::String(a), Cell::String(b)) => Ok(a.cmp(b)),
+            (Cell::String(s), Cell::Empty) => Ok(s.as_str().cmp("")),
+            (Cell::Empty, Cell::String(s)) => Ok("".cmp(s.as_str())),
+            (Cell::Boolean(a), Cell::Boolean(b)) => Ok(a.cmp(b)),
+            (a, b) => match (as_number(a), as_number(b)) {
+                (Some(a), Some(b)) => a
+                    .partial_cmp(&b)
+                    .ok_or_else(|| eyre::eyre!("Cannot compare {a} with {b}.")),
+                _ => bail!("Cannot compare \"{a}\" with \"{b}\"."),
+            },
+        }
+    }
+}
+
 impl FromStr for Cell {
     type Err = eyre::Report;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.is_empty() {
             Ok(Cell::Empty)
+        } else if let Some(source) = s.strip_prefix('=') {
+            match Formula::parse(source) {
+                Ok(formula) => Ok(Cell::Formula(Box::new(formula))),
+                Err(e) => {
+                    warn!("{e} Keeping \"{s}\" as text.");
+                    Ok(Cell::String(s.to_string()))
+                }
+            }
         } else if s.parse::<f64>().is_ok() {
             Ok(Cell::Number(s.parse::<f64>().unwrap()))
         } else if s.parse::<bool>().is_ok() {
@@ -305,6 +453,7 @@ impl std::fmt::Display for Cell {
             Cell::Number(n) => write!(f, "{}", n),
             Cell::Boolean(b) => write!(f, "{}", b),
             Cell::Empty => write!(f, ""),
+            Cell::Formula(formula) => write!(f, "{}", formula.value()),
         }
     }
 }
@@ -351,13 +500,16 @@ impl Table {
     }
     pub fn extend_headers(&mut self, headers: Vec<Cell>) {
         self.headers.cells.extend(headers);
+        for row in &mut self.rows {
+            row.cells.resize(self.headers.len(), Cell::Empty);
+        }
     }
     pub(crate) fn create_empty_row(&mut self) {
         let row = Row::new(self.headers.len());
         self.rows.push(row);
     }
     fn parse_row_splitted(&self, cell_tokens: Vec<&str>) -> Option<Row> {
-        if self.headers.len() == 0 {
+        if self.headers.is_empty() {
             warn!("Headers are not set, adding data before setting headers is not allowed.");
             suggestion!(
                 "Headers can be added using\n$ \x1b[96m{table_name} headers {header_text}\x1b[0m",
@@ -399,6 +551,569 @@ impl Table {
             self.rows.push(row);
         }
     }
+    /// Fails with a hint at the valid range when `row` is not a row of the table, rows are
+    /// reported 1-based like in A1 notation.
+    fn check_row(&self, row: usize) -> Result<()> {
+        if row < self.rows.len() {
+            return Ok(());
+        }
+        match self.rows.len() {
+            0 => suggestion!(
+                "Rows can be added with \x1b[96m{} add_row ...\x1b[0m.",
+                self.ident
+            ),
+            len => suggestion!("Did you mean row \x1b[96m{len}\x1b[0m, the last one?"),
+        }
+        bail!(
+            "Row {} is out of range, table {} has {} rows.",
+            row + 1,
+            self.ident,
+            self.rows.len()
+        )
+    }
+    fn check_column(&self, col: usize) -> Result<()> {
+        if col < self.headers.len() {
+            return Ok(());
+        }
+        if let Some(last) = self.headers.cells.last() {
+            suggestion!(
+                "Did you mean column \x1b[96m{}\x1b[0m (\"{last}\"), the last one?",
+                column_name(self.headers.len() - 1)
+            );
+        }
+        bail!(
+            "Column {} is out of range, table {} has {} columns.",
+            column_name(col),
+            self.ident,
+            self.headers.len()
+        )
+    }
+    pub fn set_cell(&mut self, row: usize, col: usize, value: Cell) -> Result<()> {
+        self.check_row(row)?;
+        self.check_column(col)?;
+        self.rows[row].cells[col] = value;
+        Ok(())
+    }
+    /// Removes `row`, formulas reading it become `#REF!` and those reading rows below it
+    /// follow them up.
+    pub fn delete_row(&mut self, row: usize) -> Result<Row> {
+        self.check_row(row)?;
+        let deleted = self.rows.remove(row);
+        self.remap_formulas(|cell| match cell.row {
+            r if r == row => None,
+            r if r > row => Some(CellRef {
+                row: r - 1,
+                col: cell.col,
+            }),
+            _ => Some(cell),
+        });
+        Ok(deleted)
+    }
+    /// Inserts a row before `row`, where `row` equal to the row count appends it. Formulas
+    /// reading rows from `row` on follow them down, the formulas of the new row are kept as
+    /// written.
+    pub fn insert_row(&mut self, row: usize, cells: Vec<&str>) -> Result<()> {
+        if row != self.rows.len() {
+            self.check_row(row)?;
+        }
+        if let Some(parsed) = self.parse_row_splitted(cells) {
+            self.remap_formulas(|cell| {
+                Some(CellRef {
+                    row: if cell.row >= row {
+                        cell.row + 1
+                    } else {
+                        cell.row
+                    },
+                    col: cell.col,
+                })
+            });
+            self.rows.insert(row, parsed);
+        }
+        Ok(())
+    }
+    /// Replaces the values of `row`, missing trailing cells become empty.
+    pub fn update_row(&mut self, row: usize, cells: Vec<&str>) -> Result<()> {
+        self.check_row(row)?;
+        if let Some(parsed) = self.parse_row_splitted(cells) {
+            self.rows[row] = parsed;
+        }
+        Ok(())
+    }
+    fn check_new_header(&self, header: &Cell) -> Result<()> {
+        if self.header_index(&header.to_string()).is_some() {
+            suggestion!(
+                "Columns can be renamed with \x1b[96m{} rename_column \"{header}\" \"new name\"\x1b[0m.",
+                self.ident
+            );
+            bail!(
+                "Column \"{header}\" already exists in table {}.",
+                self.ident
+            );
+        }
+        Ok(())
+    }
+    /// Appends a column holding `value` in every row. A formula value is filled down, its
+    /// references move along with the row as when dragging a cell in a spreadsheet, unless
+    /// their row is fixed with `$`.
+    pub fn add_column(&mut self, header: Cell, value: Cell) -> Result<usize> {
+        self.check_new_header(&header)?;
+        let col = self.push_column(header);
+        for (index, row) in self.rows.iter_mut().enumerate() {
+            row.cells[col] = match &value {
+                Cell::Formula(formula) => formula
+                    .fill_down(index)
+                    .map_or_else(|| value.clone(), |formula| Cell::Formula(Box::new(formula))),
+                value => value.clone(),
+            };
+        }
+        Ok(col)
+    }
+    pub fn drop_column(&mut self, col: usize) -> Result<Cell> {
+        self.check_column(col)?;
+        let header = self.headers.cells.remove(col);
+        for row in &mut self.rows {
+            row.cells.remove(col);
+        }
+        self.remap_formulas(|cell| match cell.col {
+            c if c == col => None,
+            c if c > col => Some(CellRef {
+                row: cell.row,
+                col: c - 1,
+            }),
+            _ => Some(cell),
+        });
+        Ok(header)
+    }
+    pub fn rename_column(&mut self, col: usize, header: Cell) -> Result<()> {
+        self.check_column(col)?;
+        if self.headers.cells[col] != header {
+            self.check_new_header(&header)?;
+        }
+        self.headers.cells[col] = header;
+        Ok(())
+    }
+    /// Moves column `from` so that it ends up at index `to`.
+    pub fn move_column(&mut self, from: usize, to: usize) -> Result<()> {
+        self.check_column(from)?;
+        self.check_column(to)?;
+        let mut order: Vec<usize> = (0..self.headers.len()).collect();
+        let moved = order.remove(from);
+        order.insert(to, moved);
+        self.permute_columns(order);
+        Ok(())
+    }
+    /// Puts `columns` first in the given order, the other columns follow in their current
+    /// order.
+    pub fn reorder_columns(&mut self, columns: &[usize]) -> Result<()> {
+        for (i, &col) in columns.iter().enumerate() {
+            self.check_column(col)?;
+            if columns[..i].contains(&col) {
+                bail!(
+                    "Column \"{}\" is listed more than once.",
+                    self.headers.cells[col]
+                );
+            }
+        }
+        let rest = (0..self.headers.len()).filter(|col| !columns.contains(col));
+        self.permute_columns(columns.iter().copied().chain(rest).collect());
+        Ok(())
+    }
+    /// Rearranges the columns so that new column `i` is old column `order[i]`.
+    fn permute_columns(&mut self, order: Vec<usize>) {
+        let permute = |cells: &mut Vec<Cell>| {
+            let mut old: Vec<Option<Cell>> = cells.drain(..).map(Some).collect();
+            cells.extend(order.iter().map(|&col| old[col].take().unwrap_or_default()));
+        };
+        permute(&mut self.headers.cells);
+        for row in &mut self.rows {
+            permute(&mut row.cells);
+        }
+        self.remap_formulas(|cell| {
+            Some(CellRef {
+                row: cell.row,
+                col: order
+                    .iter()
+                    .position(|&col| col == cell.col)
+                    .unwrap_or(cell.col),
+            })
+        });
+    }
+    /// Rewrites the references of every formula after rows or columns moved, a formula losing
+    /// one of its cells becomes `#REF!`.
+    fn remap_formulas(&mut self, map: impl Fn(CellRef) -> Option<CellRef>) {
+        for (row_idx, row) in self.rows.iter_mut().enumerate() {
+            for (col, cell) in row.cells.iter_mut().enumerate() {
+                let Cell::Formula(formula) = cell else {
+                    continue;
+                };
+                *cell = match formula.map_references(&map) {
+                    Some(formula) => Cell::Formula(Box::new(formula)),
+                    None => {
+                        warn!(
+                            "Formula ={} in {} referenced a removed cell.",
+                            formula.source(),
+                            Table::address(row_idx, col)
+                        );
+                        Cell::String("#REF!".to_string())
+                    }
+                };
+            }
+        }
+    }
+    /// Stable sort on `(column, descending)` keys, the first key deciding first. As in a
+    /// spreadsheet numbers (including numeric text, compared by value) come before text and
+    /// booleans. `NaN` and then empty cells always go last, and formulas keep reading the
+    /// same cells wherever they end up.
+    pub fn sort_rows(&mut self, keys: &[(usize, bool)]) {
+        use std::cmp::Ordering;
+        let rank = |cell: &Cell| match cell.numeric() {
+            Some(n) if n.is_nan() => 3,
+            Some(_) => 0,
+            None => match cell.value() {
+                Cell::Boolean(_) => 2,
+                Cell::Empty => 4,
+                _ => 1,
+            },
+        };
+        let compare = |a: &Cell, b: &Cell| match (a.numeric(), b.numeric()) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            _ => match (a.value(), b.value()) {
+                (Cell::Boolean(a), Cell::Boolean(b)) => a.cmp(b),
+                (a, b) => a.to_string().cmp(&b.to_string()),
+            },
+        };
+        let mut order: Vec<usize> = (0..self.rows.len()).collect();
+        order.sort_by(|&a, &b| {
+            keys.iter()
+                .map(|&(col, descending)| {
+                    let (a, b) = (&self.rows[a].cells[col], &self.rows[b].cells[col]);
+                    match (rank(a), rank(b)) {
+                        // NaN and empty cells keep their place at the end either way
+                        (rank_a, rank_b) if rank_a >= 3 || rank_b >= 3 => rank_a.cmp(&rank_b),
+                        (rank_a, rank_b) => {
+                            let order = if rank_a == rank_b {
+                                compare(a, b)
+                            } else {
+                                rank_a.cmp(&rank_b)
+                            };
+                            if descending {
+                                order.reverse()
+                            } else {
+                                order
+                            }
+                        }
+                    }
+                })
+                .find(|order| order.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+        self.reorder_rows(order);
+    }
+    /// Rearranges the rows so that new row `i` is old row `order[i]`, rows left out are
+    /// dropped. Formulas follow the rows they read, those reading a dropped row become
+    /// `#REF!`, as do ranges whose rows end up apart.
+    fn reorder_rows(&mut self, order: Vec<usize>) {
+        let mut position = vec![None; self.rows.len()];
+        for (new, &old) in order.iter().enumerate() {
+            position[old] = Some(new);
+        }
+        let mut old: Vec<Option<Row>> = self.rows.drain(..).map(Some).collect();
+        self.rows = order.iter().filter_map(|&row| old[row].take()).collect();
+        self.remap_formulas(|cell| match position.get(cell.row) {
+            Some(row) => Some(CellRef {
+                row: (*row)?,
+                col: cell.col,
+            }),
+            // references past the end were `#REF!` already
+            None => Some(cell),
+        });
+    }
+    /// Whether `row` passes every `(column, comparison, value)` condition, rows whose cell
+    /// can't be compared with the value don't.
+    fn row_matches(row: &Row, conditions: &[(usize, Operation, Cell)]) -> bool {
+        conditions.iter().all(|(col, operation, value)| {
+            matches!(
+                operation.apply(row.cells[*col].clone(), value.clone()),
+                Ok(Cell::Boolean(true))
+            )
+        })
+    }
+    pub(crate) fn retain_rows(&mut self, conditions: &[(usize, Operation, Cell)]) {
+        let kept = (0..self.rows.len())
+            .filter(|&row| Table::row_matches(&self.rows[row], conditions))
+            .collect();
+        self.reorder_rows(kept);
+    }
+    /// New table `ident` with the headers and the matching rows of this one, formulas are
+    /// copied as their values.
+    pub(crate) fn filtered_copy(
+        &self,
+        ident: String,
+        conditions: &[(usize, Operation, Cell)],
+    ) -> Table {
+        let mut table = Table::new(ident);
+        table.extend_headers(self.headers.cells.clone());
+        table.rows = self
+            .rows
+            .iter()
+            .filter(|row| Table::row_matches(row, conditions))
+            .map(|row| Row {
+                cells: row.cells.iter().map(|cell| cell.value().clone()).collect(),
+            })
+            .collect();
+        table
+    }
+    /// Aggregates the `col` cells of `rows`, leaving the cell empty when `function` has no
+    /// value for them.
+    fn aggregate<'a>(rows: impl Iterator<Item = &'a Row>, function: Function, col: usize) -> Cell {
+        function
+            .aggregate(rows.map(|row| row.cells[col].value()))
+            .map_or(Cell::Empty, Cell::Number)
+    }
+    /// Rows of this table grouped by the values in `columns`, in order of first appearance.
+    fn groups(&self, columns: &[usize]) -> Vec<(Vec<Cell>, Vec<&Row>)> {
+        let mut groups: Vec<(Vec<Cell>, Vec<&Row>)> = Vec::new();
+        let mut index = std::collections::HashMap::new();
+        for row in &self.rows {
+            let key: Vec<Cell> = columns
+                .iter()
+                .map(|&col| row.cells[col].value().clone())
+                .collect();
+            let text: Vec<String> = key.iter().map(Cell::to_string).collect();
+            let group = *index.entry(text).or_insert_with(|| {
+                groups.push((key, Vec::new()));
+                groups.len() - 1
+            });
+            groups[group].1.push(row);
+        }
+        groups
+    }
+    /// New table `ident` with a row per distinct combination of `columns` and a
+    /// `"{function} of {header}"` column per aggregate.
+    pub(crate) fn group_by(
+        &self,
+        ident: String,
+        columns: &[usize],
+        aggregates: &[(Function, usize)],
+    ) -> Table {
+        let mut table = Table::new(ident);
+        let headers = columns.iter().map(|&col| self.headers.cells[col].clone());
+        let aggregate_headers = aggregates.iter().map(|(function, col)| {
+            Cell::String(format!("{function} of {}", self.headers.cells[*col]))
+        });
+        table.extend_headers(headers.chain(aggregate_headers).collect());
+        for (key, rows) in self.groups(columns) {
+            let values = aggregates
+                .iter()
+                .map(|&(function, col)| Table::aggregate(rows.iter().copied(), function, col));
+            table.rows.push(Row {
+                cells: key.into_iter().chain(values).collect(),
+            });
+        }
+        table
+    }
+    /// New table `ident` with the values of `rows_col` down the side and those of
+    /// `columns_col` across the top, each cell aggregating the rows having both values.
+    pub(crate) fn pivot(
+        &self,
+        ident: String,
+        rows_col: usize,
+        columns_col: usize,
+        (function, col): (Function, usize),
+    ) -> Table {
+        let mut table = Table::new(ident);
+        let columns = self.groups(&[columns_col]);
+        let headers = columns.iter().map(|(key, _)| match &key[0] {
+            Cell::Empty => Cell::String("(empty)".to_string()),
+            value => value.clone(),
+        });
+        table.extend_headers(
+            std::iter::once(self.headers.cells[rows_col].clone())
+                .chain(headers)
+                .collect(),
+        );
+        for (key, rows) in self.groups(&[rows_col]) {
+            let values = columns.iter().map(|(column_key, _)| {
+                let mut matching = rows
+                    .iter()
+                    .copied()
+                    .filter(|row| {
+                        row.cells[columns_col].value().to_string() == column_key[0].to_string()
+                    })
+                    .peekable();
+                match matching.peek() {
+                    Some(_) => Table::aggregate(matching, function, col),
+                    None => Cell::Empty,
+                }
+            });
+            table.rows.push(Row {
+                cells: key.into_iter().chain(values).collect(),
+            });
+        }
+        table
+    }
+    /// New table `ident` with the columns of both tables, a row per pair of rows whose
+    /// `keys` hold the same value. Keys of the same name become one column, other clashing
+    /// headers get the table name appended, e.g. `name (orders)`.
+    pub fn join(
+        &self,
+        other: &Table,
+        ident: String,
+        (left_key, right_key): (usize, usize),
+        kind: JoinKind,
+    ) -> Table {
+        let merged_key = self.headers.cells[left_key] == other.headers.cells[right_key];
+        let right_cols: Vec<usize> = (0..other.headers.len())
+            .filter(|&col| !(merged_key && col == right_key))
+            .collect();
+        let clashes = |header: &Cell| {
+            self.headers.cells.contains(header)
+                && right_cols
+                    .iter()
+                    .any(|&col| &other.headers.cells[col] == header)
+        };
+        let rename = |header: &Cell, table: &Table| {
+            if clashes(header) {
+                Cell::String(format!("{header} ({})", table.ident))
+            } else {
+                header.clone()
+            }
+        };
+        let mut table = Table::new(ident);
+        table.extend_headers(
+            self.headers
+                .cells
+                .iter()
+                .map(|header| rename(header, self))
+                .chain(
+                    right_cols
+                        .iter()
+                        .map(|&col| rename(&other.headers.cells[col], other)),
+                )
+                .collect(),
+        );
+
+        let key = |row: &Row, col: usize| match row.cells[col].value() {
+            Cell::Empty => None,
+            value => Some(value.to_string()),
+        };
+        let values = |row: &Row, cols: &mut dyn Iterator<Item = usize>| -> Vec<Cell> {
+            cols.map(|col| row.cells[col].value().clone()).collect()
+        };
+        let mut matched_right = vec![false; other.rows.len()];
+        for row in &self.rows {
+            let mut matched = false;
+            for (right_idx, right) in other.rows.iter().enumerate() {
+                if key(row, left_key).is_some() && key(row, left_key) == key(right, right_key) {
+                    matched = true;
+                    matched_right[right_idx] = true;
+                    let mut cells = values(row, &mut (0..self.headers.len()));
+                    cells.extend(values(right, &mut right_cols.iter().copied()));
+                    table.rows.push(Row { cells });
+                }
+            }
+            if !matched && kind != JoinKind::Inner {
+                let mut cells = values(row, &mut (0..self.headers.len()));
+                cells.resize(table.headers.len(), Cell::Empty);
+                table.rows.push(Row { cells });
+            }
+        }
+        if kind == JoinKind::Outer {
+            let unmatched = other.rows.iter().zip(matched_right).filter(|(_, m)| !m);
+            for (right, _) in unmatched {
+                let mut cells = vec![Cell::Empty; self.headers.len()];
+                if merged_key {
+                    cells[left_key] = right.cells[right_key].value().clone();
+                }
+                cells.extend(values(right, &mut right_cols.iter().copied()));
+                table.rows.push(Row { cells });
+            }
+        }
+        table
+    }
+    /// Rows of `other` rearranged to the columns of this table, which must have the same
+    /// headers in any order. Formulas are copied as their values.
+    pub fn rows_from(&self, other: &Table) -> Result<Vec<Row>> {
+        let missing = |from: &Table, to: &Table| -> Vec<String> {
+            from.headers
+                .cells
+                .iter()
+                .filter(|header| !to.headers.cells.contains(header))
+                .map(|header| format!("\"{header}\""))
+                .collect()
+        };
+        let mut differences = Vec::new();
+        for (table, only) in [(other, missing(other, self)), (self, missing(self, other))] {
+            if !only.is_empty() {
+                differences.push(format!("{} only has {}", table.ident, only.join(", ")));
+            }
+        }
+        if !differences.is_empty() {
+            if let Some(header) = missing(other, self).first() {
+                suggestion!(
+                    "Columns can be added with \x1b[96m{} add_column {header}\x1b[0m.",
+                    self.ident
+                );
+            }
+            bail!(
+                "Headers of {} and {} don't match, {}.",
+                self.ident,
+                other.ident,
+                differences.join(", ")
+            );
+        }
+        let cols: Vec<usize> = self
+            .headers
+            .cells
+            .iter()
+            .map(|header| other.header_index(&header.to_string()).unwrap_or_default())
+            .collect();
+        Ok(other
+            .rows
+            .iter()
+            .map(|row| Row {
+                cells: cols
+                    .iter()
+                    .map(|&col| row.cells[col].value().clone())
+                    .collect(),
+            })
+            .collect())
+    }
+    /// Replaces headers and rows with parsed CSV records. Without `has_headers` the columns
+    /// are named "Column 1", "Column 2" and so on.
+    pub fn load_records(&mut self, mut records: Vec<Vec<String>>, has_headers: bool) {
+        let headers = if has_headers && !records.is_empty() {
+            records
+                .remove(0)
+                .iter()
+                .map(|header| Cell::from_str(header).unwrap_or_default())
+                .collect()
+        } else {
+            let width = records.iter().map(Vec::len).max().unwrap_or(0);
+            (1..=width)
+                .map(|i| Cell::String(format!("Column {i}")))
+                .collect()
+        };
+        self.headers = Row { cells: headers };
+        let width = self.headers.len();
+        let truncated = records.iter().filter(|record| record.len() > width).count();
+        if truncated > 0 {
+            warn!(
+                "{truncated} row(s) are wider than the headers ({width} columns), truncating them."
+            );
+        }
+        self.rows = records
+            .into_iter()
+            .map(|record| Row {
+                cells: record
+                    .iter()
+                    .map(|cell| Cell::from_str(cell).unwrap_or_default())
+                    .chain(std::iter::repeat(Cell::Empty))
+                    .take(width)
+                    .collect(),
+            })
+            .collect();
+    }
     pub fn get_cell(&self, row: usize, col: usize) -> Result<&Cell> {
         self.rows
             .get(row)
@@ -415,6 +1130,117 @@ impl Table {
             .get_mut(col)
             .ok_or(eyre::eyre!("Column index out of bound."))
     }
+    /// Recomputes every formula after the cells it reads, failing on circular references.
+    ///
+    /// Cells taking part in a cycle are set to `#CYCLE!`, the other formulas are still computed.
+    pub fn recalculate(&mut self) -> Result<()> {
+        let mut formulas = std::collections::HashMap::new();
+        for (row, cells) in self.rows.iter().enumerate() {
+            for (col, cell) in cells.cells.iter().enumerate() {
+                if let Cell::Formula(formula) = cell {
+                    formulas.insert(CellRef { row, col }, formula.references());
+                }
+            }
+        }
+        if formulas.is_empty() {
+            return Ok(());
+        }
+        let (order, cyclic, cycle) = evaluation_order(&formulas);
+        for cell in &cyclic {
+            if let Cell::Formula(formula) = &mut self.rows[cell.row].cells[cell.col] {
+                formula.set_value(Cell::String("#CYCLE!".to_string()));
+            }
+        }
+        for cell in order.into_iter().filter(|cell| !cyclic.contains(cell)) {
+            let Cell::Formula(formula) = &self.rows[cell.row].cells[cell.col] else {
+                continue;
+            };
+            let value = formula.evaluate(self);
+            if let Cell::Formula(formula) = &mut self.rows[cell.row].cells[cell.col] {
+                formula.set_value(value);
+            }
+        }
+        match cycle {
+            Some(cycle) => bail!("Circular reference in table {}: {cycle}", self.ident),
+            None => Ok(()),
+        }
+    }
+    /// Appends an empty column named `header`, returning its index.
+    pub fn push_column(&mut self, header: Cell) -> usize {
+        self.headers.cells.push(header);
+        for row in &mut self.rows {
+            row.cells.resize(self.headers.len(), Cell::Empty);
+        }
+        self.headers.len() - 1
+    }
+    /// Top left and bottom right cell of `range` as `(row, col)` indices, whichever corners
+    /// were written first. Whole columns span every row.
+    pub fn resolve_range(&self, range: &CellRange) -> Result<((usize, usize), (usize, usize))> {
+        let last_row = || {
+            self.rows
+                .len()
+                .checked_sub(1)
+                .ok_or_else(|| eyre::eyre!("Table {} has no rows to apply on.", self.ident))
+        };
+        Ok(match range {
+            CellRange::Cells(from, to) => (
+                (from.row.min(to.row), from.col.min(to.col)),
+                (from.row.max(to.row), from.col.max(to.col)),
+            ),
+            CellRange::Columns(from, to) => ((0, *from.min(to)), (last_row()?, *from.max(to))),
+            CellRange::Header(name) => {
+                let col = self
+                    .header_index(name)
+                    .ok_or_else(|| self.missing_column(name))?;
+                ((0, col), (last_row()?, col))
+            }
+        })
+    }
+    fn missing_column(&self, name: &str) -> eyre::Report {
+        eyre::eyre!(
+            "No column named \"{name}\" in table {}, columns are {}.",
+            self.ident,
+            self.headers
+                .cells
+                .iter()
+                .map(|header| format!("\"{header}\""))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+    /// Index of a column given by header name or 1-based number.
+    pub fn column_position(&self, column: &ColumnRef) -> Result<usize> {
+        match column {
+            ColumnRef::Index(index) => {
+                let col = index.saturating_sub(1);
+                self.check_column(col)?;
+                Ok(col)
+            }
+            ColumnRef::Name(name) => self
+                .header_index(name)
+                .ok_or_else(|| self.missing_column(name)),
+        }
+    }
+    /// Parses an address written as `B2`, `A1:C10`, `B:B` or a header name.
+    pub fn parse_address(&self, text: &str) -> Result<((usize, usize), (usize, usize))> {
+        let range = CellRange::parse(text).unwrap_or_else(|| CellRange::Header(text.to_string()));
+        self.resolve_range(&range)
+    }
+    /// A1 name of the cell at `(row, col)`, e.g. `B3` for `(2, 1)`.
+    pub fn address(row: usize, col: usize) -> String {
+        CellRef { row, col }.to_string()
+    }
+    /// Spreadsheet letters of column `col`, e.g. `AA` for 26.
+    pub fn column_letters(col: usize) -> String {
+        column_name(col)
+    }
+    /// Index of the first column whose header is `name`.
+    pub fn header_index(&self, name: &str) -> Option<usize> {
+        self.headers
+            .cells
+            .iter()
+            .position(|header| header.to_string() == name)
+    }
     pub fn get_column(&self, col: usize) -> Result<Vec<&Cell>> {
         self.rows
             .iter()
@@ -430,95 +1256,378 @@ impl Table {
             .get(row)
             .ok_or(eyre::eyre!("Row index out of bound."))
     }
-    pub fn table_view(&self) -> Result<()> {
-        use chatgpt::prelude::*;
-
+    /// CSV with title for a prompt, keeping an evenly spaced sample of rows when the whole
+    /// table is longer than `max_chars`.
+    pub fn to_prompt_csv(&self, max_chars: usize) -> String {
+        let mut csv = String::new();
+        push_csv_record(&mut csv, std::iter::once(self.title.as_str()));
+        push_csv_record(
+            &mut csv,
+            self.headers.cells.iter().map(|cell| cell.to_string()),
+        );
+        let rows: Vec<String> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let mut record = String::new();
+                push_csv_record(&mut record, row.cells.iter().map(|cell| cell.to_string()));
+                record
+            })
+            .collect();
+        let total = rows.len();
+        let sample = |count: usize| (0..count).map(move |i| i * total / count);
+        let length = |count: usize| sample(count).map(|i| rows[i].len()).sum::<usize>();
+        if csv.len() + length(total) <= max_chars {
+            rows.iter().for_each(|row| csv.push_str(row));
+            return csv;
+        }
+        let note = format!("... {total} of {total} rows shown, sampled evenly\r\n");
+        let available = max_chars.saturating_sub(csv.len() + note.len());
+        let average = length(total) / total.max(1);
+        let mut count = (available / average.max(1)).min(total);
+        while count > 0 && length(count) > available {
+            count -= 1;
+        }
+        sample(count).for_each(|i| csv.push_str(&rows[i]));
+        csv.push_str(&format!(
+            "... {count} of {total} rows shown, sampled evenly\r\n"
+        ));
+        csv
+    }
+    /// Title, headers and row `row` as CSV for a prompt about that row.
+    pub fn row_prompt_csv(&self, row: usize) -> Result<String> {
+        let mut csv = String::new();
+        push_csv_record(&mut csv, std::iter::once(self.title.as_str()));
+        push_csv_record(
+            &mut csv,
+            self.headers.cells.iter().map(|cell| cell.to_string()),
+        );
+        push_csv_record(
+            &mut csv,
+            self.get_row(row)?.cells.iter().map(|cell| cell.to_string()),
+        );
+        Ok(csv)
+    }
+    /// Writes the table to `table.html` and a plot to `plot_path`, suggested by `llm` when given
+    /// and chosen locally otherwise.
+    pub fn table_view(&self, llm: Option<&dyn LlmBackend>, plot_path: &str) -> Result<()> {
+        let graph = match llm {
+            Some(llm) => self.plot_suggestion(llm)?,
+            None => self.recommended_plot(),
+        };
+        self.render_plot(&[graph], plot_path)?;
+        let html_table = self.to_html()?;
+        // write table to table.html
+        let mut file = File::create("table.html")?;
+        file.write_all(b"<!DOCTYPE html><html><body><style>table,th,td{border:1px solid black;padding:3px;margin:2px;}</style>")?;
+        file.write_all(html_table.as_bytes())?;
+        file.write_all(b"</body></html>")?;
+        Ok(())
+    }
+    /// Asks `llm` which plot suits the table, choosing one locally when the reply is unusable.
+    pub fn plot_suggestion(&self, llm: &dyn LlmBackend) -> Result<ResponsePlotType> {
         let prompt_text = format!(
             r#"I am working with CSV file format, and want to make a plot out of following data:
 Table Name: {table}
-what kind of plot should I use, give response from one of "Scatter", "Histogram", "Bargraph", followed by the Column number used to be plot in next line:
-For example:
-Bargraph
-x = 1
-y = 2
-this will indicate to plot column 1 on x-axis and column 2 on y-axis in a bar graph.
-For "Bargraph" and "Scatter" mention both x and y value (strictly an integer which represents column number) and for "Histogram" only mention the x value (strictly an integer which represents column number).
-You can also respond with "None" in case no plot is relevant and "Data Insufficient" in case data is insufficient."#,
+What kind of plot should I use? Reply with a single JSON object and nothing else, in the form:
+{{"plot": "bar", "x": 1, "y": 2}}
+where "plot" is one of "bar", "line", "scatter", "histogram", "pie", "none" or "data insufficient", and "x" and "y" are column numbers starting from 1.
+For "bar", "line" and "scatter" give both x and y, for "histogram" only x, and for "pie" x as the label column and optionally y as the value column.
+Use "none" in case no plot is relevant and "data insufficient" in case data is insufficient."#,
             table = self.to_csv()?
         );
-        // println!("Prompt: {}", prompt_text);
-        let client = ChatGPT::new(env!(
-            "OPENAI_KEY",
-            "OpenAI key not provided! Required to use prompt."
-        ))?;
-        use tokio::runtime::Runtime;
-        let rt = Runtime::new().unwrap();
-
-        let prompt_resp = rt.block_on(async { client.send_message(prompt_text).await })?;
-        let prompt_resp = prompt_resp.message().content.as_str();
-        // let prompt_resp = "Bargraph\nx = 1\ny = 2";
-        println!("{}", prompt_resp);
-        let graph = ResponsePlotType::from_str(prompt_resp)
-            .map_err(|_| eyre::eyre!("Invalid response from prompt: {}", prompt_resp))?;
-        use plotly::{Bar, Histogram, Plot, Scatter};
-        let mut plot = Plot::new();
-        match graph {
-            ResponsePlotType::Bargraph((x, y)) => {
-                let xdata = self.get_column(x)?.into_iter().map(|x| x.clone()).collect();
-                let ydata = self.get_column(y)?.into_iter().map(|x| x.clone()).collect();
-                let trace = Bar::new(xdata, ydata)
-                    .name(self.title())
-                    .x_axis(self.headers.cells[x].to_string())
-                    .y_axis(self.headers.cells[y].to_string());
-                plot.add_trace(trace);
+        let reply = match llm.complete(&prompt_text) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("{} backend failed: {e}", llm.name());
+                suggestion!("Choosing a plot locally instead.");
+                return Ok(self.recommended_plot());
             }
-            ResponsePlotType::Histogram(x) => {
-                let xdata = self.get_column(x)?.into_iter().map(|x| x.clone()).collect();
-                let trace = Histogram::new(xdata)
-                    .name(self.title())
-                    .x_axis(self.headers.cells[x].to_string());
-                plot.add_trace(trace);
+        };
+        println!("{}", reply);
+        match ResponsePlotType::from_str(&reply) {
+            Ok(graph) if graph.fits(self.headers.len()) => Ok(graph),
+            Ok(graph) => {
+                warn!("Suggested plot {graph:?} uses columns the table does not have.");
+                suggestion!("Choosing a plot locally instead.");
+                Ok(self.recommended_plot())
             }
-            ResponsePlotType::Piechart(_) => todo!(),
-            ResponsePlotType::Scatterplot((x, y)) => {
-                let xdata = self.get_column(x)?.into_iter().map(|x| x.clone()).collect();
-                let ydata = self.get_column(y)?.into_iter().map(|x| x.clone()).collect();
-                let trace = Scatter::new(xdata, ydata)
-                    .name(self.title())
-                    .x_axis(self.headers.cells[x].to_string())
-                    .y_axis(self.headers.cells[y].to_string());
-                plot.add_trace(trace);
+            Err(()) => {
+                warn!("Could not understand the plot suggestion.");
+                suggestion!("Choosing a plot locally instead.");
+                Ok(self.recommended_plot())
             }
-            ResponsePlotType::DataInsufficient => println!("Data is insufficient to make a plot."),
-            ResponsePlotType::None => println!("No plot is suitable for given data."),
         }
-        plot.write_html("plot.html");
-        let html_table = self.to_html()?;
-        // write table to table.html
-        let mut file = File::create("table.html")?;
-        file.write(b"<!DOCTYPE html><html><body><style>table,th,td{border:1px solid black;padding:3px;margin:2px;}</style>")?;
-        file.write_all(html_table.as_bytes())?;
-        file.write(b"</body></html>")?;
+    }
+    /// Picks a plot from the column types, their cardinality and the row count, without any
+    /// LLM:
+    ///
+    /// - a category repeating over a few values (with a non-negative number) as a pie chart,
+    /// - categories against numbers as a bar graph,
+    /// - two numeric columns as a scatter plot,
+    /// - a single numeric column as a histogram.
+    pub fn recommended_plot(&self) -> ResponsePlotType {
+        let rows = self.rows.len();
+        let columns: Vec<_> = (0..self.headers.len())
+            .filter_map(|col| ColumnProfile::new(self, col))
+            .collect();
+        if rows == 0 || columns.is_empty() {
+            return ResponsePlotType::DataInsufficient;
+        }
+        let numeric: Vec<_> = columns.iter().filter(|c| c.numeric).collect();
+        let categorical: Vec<_> = columns.iter().filter(|c| !c.numeric).collect();
+
+        let share = categorical
+            .iter()
+            .find(|c| c.distinct < rows && (2..=PIE_MAX_SLICES).contains(&c.distinct));
+        if let Some(labels) = share {
+            let values = numeric.iter().find(|c| c.non_negative).map(|c| c.index);
+            return ResponsePlotType::Piechart((labels.index, values));
+        }
+        match (categorical.first(), numeric.as_slice()) {
+            (Some(x), [y, ..]) => ResponsePlotType::Bargraph((x.index, y.index)),
+            (None, [x, y, ..]) if rows > 1 => ResponsePlotType::Scatterplot((x.index, y.index)),
+            (None, [x, ..]) if rows > 1 => ResponsePlotType::Histogram(x.index),
+            (None, _) => ResponsePlotType::DataInsufficient,
+            (Some(_), []) => ResponsePlotType::None,
+        }
+    }
+    /// Draws `graphs` with plotly into a single HTML file at `path`, one trace per graph.
+    pub fn render_plot(&self, graphs: &[ResponsePlotType], path: &str) -> Result<()> {
+        use plotly::{common::Mode, Bar, Histogram, Plot, Scatter};
+        let mut plot = Plot::new();
+        let header = |col: usize| self.headers.cells[col].to_string();
+        // a single series is named after the table, several after their y column
+        let name = |col: usize| {
+            if graphs.len() > 1 {
+                header(col)
+            } else {
+                self.title().to_string()
+            }
+        };
+        let column = |col: usize| -> Result<Vec<Cell>> {
+            Ok(self.get_column(col)?.into_iter().cloned().collect())
+        };
+        for graph in graphs {
+            match *graph {
+                ResponsePlotType::Bargraph((x, y)) => {
+                    let trace = Bar::new(column(x)?, column(y)?)
+                        .name(name(y))
+                        .x_axis(header(x))
+                        .y_axis(header(y));
+                    plot.add_trace(trace);
+                }
+                ResponsePlotType::Histogram(x) => {
+                    let trace = Histogram::new(column(x)?).name(name(x)).x_axis(header(x));
+                    plot.add_trace(trace);
+                }
+                ResponsePlotType::Piechart((labels, values)) => {
+                    plot.add_trace(self.pie_trace(labels, values)?);
+                }
+                ResponsePlotType::Scatterplot((x, y)) => {
+                    let trace = Scatter::new(column(x)?, column(y)?)
+                        .mode(Mode::Markers)
+                        .name(name(y))
+                        .x_axis(header(x))
+                        .y_axis(header(y));
+                    plot.add_trace(trace);
+                }
+                ResponsePlotType::Linegraph((x, y)) => {
+                    let trace = Scatter::new(column(x)?, column(y)?)
+                        .mode(Mode::Lines)
+                        .name(name(y))
+                        .x_axis(header(x))
+                        .y_axis(header(y));
+                    plot.add_trace(trace);
+                }
+                ResponsePlotType::DataInsufficient => {
+                    println!("Data is insufficient to make a plot.")
+                }
+                ResponsePlotType::None => println!("No plot is suitable for given data."),
+            }
+        }
+        plot.write_html(path);
         Ok(())
     }
+    /// Pie slices labelled by column `labels`, sized by the numbers in column `values` or by
+    /// how often each label occurs.
+    fn pie_trace<'a>(&'a self, labels: usize, values: Option<usize>) -> Result<Box<PieTrace>> {
+        let cell = |row: &'a Row, col: usize| {
+            row.cells
+                .get(col)
+                .ok_or(eyre::eyre!("Column index out of bound."))
+        };
+        let mut slices: Vec<(String, f64)> = Vec::new();
+        for row in &self.rows {
+            let label = cell(row, labels)?.to_string();
+            let value = match values {
+                Some(col) => match cell(row, col)?.value() {
+                    Cell::Number(n) => *n,
+                    Cell::Empty => 0_f64,
+                    Cell::String(s) if s.trim().parse::<f64>().is_ok() => s.trim().parse()?,
+                    other => bail!(
+                        "Pie chart values must be numbers, found \"{other}\" in column {}.",
+                        self.headers.cells[col]
+                    ),
+                },
+                None => 1_f64,
+            };
+            match slices.iter_mut().find(|(existing, _)| *existing == label) {
+                Some((_, total)) => *total += value,
+                None => slices.push((label, value)),
+            }
+        }
+        let (labels, values) = slices.into_iter().unzip();
+        Ok(Box::new(PieTrace {
+            kind: "pie",
+            name: self.title().to_string(),
+            labels,
+            values,
+        }))
+    }
+}
+
+/// Most distinct labels a locally recommended pie chart may have.
+const PIE_MAX_SLICES: usize = 8;
+
+/// What the local plot recommender knows about a column.
+struct ColumnProfile {
+    index: usize,
+    /// Every non-empty cell is a number.
+    numeric: bool,
+    non_negative: bool,
+    /// Number of distinct non-empty values.
+    distinct: usize,
+}
+
+impl ColumnProfile {
+    /// Profile of column `index`, `None` when the column has no values.
+    fn new(table: &Table, index: usize) -> Option<ColumnProfile> {
+        let cells: Vec<_> = table
+            .rows
+            .iter()
+            .filter_map(|row| row.cells.get(index).map(Cell::value))
+            .filter(|cell| **cell != Cell::Empty)
+            .collect();
+        if cells.is_empty() {
+            return None;
+        }
+        let mut values: Vec<String> = cells.iter().map(|cell| cell.to_string()).collect();
+        values.sort();
+        values.dedup();
+        Some(ColumnProfile {
+            index,
+            numeric: cells.iter().all(|cell| matches!(cell, Cell::Number(_))),
+            non_negative: cells
+                .iter()
+                .all(|cell| matches!(cell, Cell::Number(n) if *n >= 0_f64)),
+            distinct: values.len(),
+        })
+    }
+}
+
+/// Pie trace, the plotly version in use has no pie chart of its own.
+#[derive(Serialize, Clone)]
+struct PieTrace {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    labels: Vec<String>,
+    values: Vec<f64>,
+}
+
+impl plotly::Trace for PieTrace {
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Quotes a field if it contains a delimiter, double quote or line break.
+pub fn escape_csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Parses CSV text into records, handling quoted fields as described in RFC 4180.
+/// Blank lines are skipped.
+pub fn parse_csv(text: &str, delimiter: char) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let (mut line, mut quote_line) = (1, 1);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                in_quotes = true;
+                quote_line = line;
+            }
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                line += 1;
+                record.push(std::mem::take(&mut field));
+                if record.len() == 1 && record[0].is_empty() {
+                    record.clear();
+                } else {
+                    records.push(std::mem::take(&mut record));
+                }
+            }
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        bail!("Quoted field starting in line {quote_line} was not closed.");
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn push_csv_record<T: AsRef<str>>(csv: &mut String, fields: impl Iterator<Item = T>) {
+    for (idx, field) in fields.enumerate() {
+        if idx > 0 {
+            csv.push(',');
+        }
+        csv.push_str(&escape_csv_field(field.as_ref()));
+    }
+    csv.push_str("\r\n");
 }
 
 impl Save for Table {
-    fn to_csv(&self) -> Result<String> {
+    fn to_csv_with_title(&self, title: bool) -> Result<String> {
         let mut csv = String::new();
-        csv.push_str(&self.title);
-        csv.push('\n');
-        for cell in &self.headers.cells {
-            csv.push_str(cell.to_string().as_str());
-            csv.push(',');
+        if title {
+            push_csv_record(&mut csv, std::iter::once(self.title.as_str()));
         }
-        csv.push_str("\n");
+        push_csv_record(
+            &mut csv,
+            self.headers.cells.iter().map(|cell| cell.to_string()),
+        );
         for row in &self.rows {
-            for cell in &row.cells {
-                csv.push_str(cell.to_string().as_str());
-                csv.push(',');
-            }
-            csv.push('\n');
+            push_csv_record(&mut csv, row.cells.iter().map(|cell| cell.to_string()));
         }
         Ok(csv)
     }